@@ -0,0 +1,381 @@
+use crate::display::Colors;
+use crate::error::LspError;
+use crate::request::*;
+use crate::transport::{
+    ServerStreams, connect_socket, consume_json_rpc_message, start_server_process,
+};
+use crate::{Count, process_file};
+use serde_json::Value;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::process::Child;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Every message the server sends, in order, ending with `Ok(None)` when it closes the stream.
+pub type Messages = Receiver<Result<Option<Value>, LspError>>;
+
+// Reads on its own thread so a caller can stop waiting on a server that never answers.
+pub fn read_messages(reader: Box<dyn Read + Send>, colors: Colors, echo_raw: bool) -> Messages {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let message = consume_json_rpc_message(&mut reader, colors, echo_raw);
+            let eof = matches!(message, Ok(None));
+            if sender.send(message).is_err() || eof {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// What a client hands back to a caller that takes over the connection after the handshake.
+pub struct ClientParts {
+    pub messages: Messages,
+    pub writer: Box<dyn Write + Send>,
+    pub child: Option<Child>,
+    pub notifications: Vec<Value>,
+}
+
+pub struct LspClient {
+    messages: Messages,
+    writer: Box<dyn Write + Send>,
+    child: Option<Child>,
+    count: Count,
+    timeout: Option<Duration>,
+    settings: Value,
+    file_uri: Option<String>,
+    notifications: Vec<Value>,
+}
+
+impl LspClient {
+    pub fn new(streams: ServerStreams) -> Self {
+        Self::with_echo(streams, Colors::new(false), false)
+    }
+
+    // Like `new`, printing the exact bytes of every frame received when `echo_raw` is set.
+    pub fn with_echo((reader, writer): ServerStreams, colors: Colors, echo_raw: bool) -> Self {
+        Self {
+            messages: read_messages(reader, colors, echo_raw),
+            writer,
+            child: None,
+            count: Count(0),
+            timeout: None,
+            settings: Value::Null,
+            file_uri: None,
+            notifications: Vec::new(),
+        }
+    }
+
+    pub fn spawn(command: &str, args: &[String]) -> Result<Self, LspError> {
+        let mut child = start_server_process(command, args, None, &[])?;
+        let stdin = child.stdin.take().expect("Failed to open stdin");
+        let stdout = child.stdout.take().expect("Failed to open stdout");
+
+        // Drain stderr so a chatty server can't block on a full pipe.
+        if let Some(mut stderr) = child.stderr.take() {
+            thread::spawn(move || io::copy(&mut stderr, &mut io::sink()));
+        }
+
+        let mut client = Self::new((Box::new(stdout), Box::new(stdin)));
+        client.child = Some(child);
+        Ok(client)
+    }
+
+    pub fn connect(address: &str) -> Result<Self, LspError> {
+        connect_socket(address).map(Self::new)
+    }
+
+    // How long to wait for each response before giving up with `LspError::Timeout`.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    // The settings `workspace/configuration` requests are answered from.
+    pub fn set_settings(&mut self, settings: Value) {
+        self.settings = settings;
+    }
+
+    // Everything received that wasn't a response to our requests, server requests included.
+    pub fn take_notifications(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    pub fn into_parts(self) -> ClientParts {
+        ClientParts {
+            messages: self.messages,
+            writer: self.writer,
+            child: self.child,
+            notifications: self.notifications,
+        }
+    }
+
+    pub fn notify(&mut self, notification: &[u8]) -> Result<(), LspError> {
+        self.writer
+            .write_all(notification)
+            .and_then(|()| self.writer.flush())
+            .map_err(|e| LspError::io("Failed to write request", e))
+    }
+
+    fn receive(&self, deadline: Option<Instant>) -> Result<Value, LspError> {
+        let message = match deadline {
+            Some(deadline) => {
+                match self
+                    .messages
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => {
+                        return Err(LspError::Timeout(format!(
+                            "No response within {}s",
+                            self.timeout.unwrap_or_default().as_secs()
+                        )));
+                    }
+                    Err(RecvTimeoutError::Disconnected) => Ok(None),
+                }
+            }
+            None => self.messages.recv().unwrap_or(Ok(None)),
+        };
+
+        message?
+            .ok_or_else(|| LspError::Exited("Language server closed the connection".to_string()))
+    }
+
+    // Sends a request and returns the whole response message, answering any requests the
+    // server makes of us while we wait.
+    pub fn call(&mut self, request: &Request) -> Result<Value, LspError> {
+        self.notify(&request.bytes)?;
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let message = self.receive(deadline)?;
+
+            if let Some(response) = server_request_response(&message, &self.settings) {
+                self.notify(&response)?;
+            }
+
+            if message.get("method").is_none()
+                && message.get("id").and_then(Value::as_i64) == Some(i64::from(request.id))
+            {
+                return Ok(message);
+            }
+
+            self.notifications.push(message);
+        }
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Value, LspError> {
+        let message = self.call(request)?;
+        if let Some(error) = message.get("error") {
+            return Err(LspError::server(error));
+        }
+
+        Ok(message.get("result").cloned().unwrap_or(Value::Null))
+    }
+
+    fn file_uri(&self) -> Result<String, LspError> {
+        self.file_uri
+            .clone()
+            .ok_or_else(|| LspError::Usage("No document is open".to_string()))
+    }
+
+    pub fn initialize(&mut self, root: &Path) -> Result<Value, LspError> {
+        let root = fs::canonicalize(root)
+            .map_err(|e| LspError::io("Error: Unable to resolve workspace root", e))?;
+
+        let id = self.count.inc();
+        let result = self.request(&initialize_request(id, &root, None))?;
+        self.notify(&initialized_notification())?;
+
+        Ok(result)
+    }
+
+    pub fn did_open(&mut self, file_path: &Path) -> Result<(), LspError> {
+        let (file_uri, source) = process_file(file_path)?;
+        self.notify(&did_open_request(
+            &file_uri,
+            &source,
+            language_id(file_path),
+            1,
+        ))?;
+        self.file_uri = Some(file_uri);
+
+        Ok(())
+    }
+
+    pub fn definition(&mut self, line: usize, character: usize) -> Result<Value, LspError> {
+        let file_uri = self.file_uri()?;
+        let id = self.count.inc();
+        self.request(&definition_request(id, &file_uri, line, character))
+    }
+
+    pub fn references(&mut self, line: usize, character: usize) -> Result<Value, LspError> {
+        let file_uri = self.file_uri()?;
+        let id = self.count.inc();
+        self.request(&reference_request(id, &file_uri, line, character, true))
+    }
+
+    pub fn document_symbols(&mut self) -> Result<Value, LspError> {
+        let file_uri = self.file_uri()?;
+        let id = self.count.inc();
+        self.request(&document_symbol_request(id, &file_uri))
+    }
+
+    pub fn shutdown(mut self) -> Result<(), LspError> {
+        if let Some(file_uri) = self.file_uri.take() {
+            self.notify(&did_close_request(&file_uri))?;
+        }
+
+        let id = self.count.inc();
+        self.request(&shutdown_request(id))?;
+        self.notify(&exit_request())?;
+
+        if let Some(mut child) = self.child.take() {
+            child
+                .wait()
+                .map_err(|e| LspError::io("Failed to wait on child process", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Written {
+        fn messages(&self) -> Vec<Value> {
+            let written = self.0.lock().unwrap().clone();
+            let mut reader = BufReader::new(Cursor::new(written));
+            std::iter::from_fn(|| {
+                consume_json_rpc_message(&mut reader, Colors::new(false), false).unwrap()
+            })
+            .collect()
+        }
+    }
+
+    fn client(server_output: &[Value]) -> (LspClient, Written) {
+        let frames: Vec<u8> = server_output
+            .iter()
+            .flat_map(|message| {
+                let body = message.to_string();
+                format!("Content-Length: {}\r\n\r\n{body}", body.len()).into_bytes()
+            })
+            .collect();
+        let written = Written::default();
+        let client = LspClient::new((Box::new(Cursor::new(frames)), Box::new(written.clone())));
+        (client, written)
+    }
+
+    #[test]
+    fn initialize_sends_initialized_after_the_response() {
+        let (mut client, written) = client(&[json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": { "capabilities": { "hoverProvider": true } }
+        })]);
+
+        let result = client.initialize(Path::new(".")).unwrap();
+        assert_eq!(result, json!({ "capabilities": { "hoverProvider": true } }));
+
+        let methods: Vec<Value> = written
+            .messages()
+            .into_iter()
+            .map(|m| m["method"].clone())
+            .collect();
+        assert_eq!(methods, [json!("initialize"), json!("initialized")]);
+    }
+
+    #[test]
+    fn call_keeps_notifications_and_answers_server_requests() {
+        let (mut client, written) = client(&[
+            json!({ "jsonrpc": "2.0", "method": "window/logMessage", "params": {} }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": "c1",
+                "method": "workspace/configuration",
+                "params": { "items": [{}] }
+            }),
+            json!({ "jsonrpc": "2.0", "id": 1, "result": null }),
+        ]);
+        client.set_settings(json!({ "tabSize": 4 }));
+
+        let response = client.call(&shutdown_request(1)).unwrap();
+        assert_eq!(response["id"], 1);
+
+        let notifications = client.take_notifications();
+        assert_eq!(notifications.len(), 2);
+        assert!(client.take_notifications().is_empty());
+
+        let written = written.messages();
+        assert_eq!(written[1]["id"], "c1");
+        assert_eq!(written[1]["result"], json!([{ "tabSize": 4 }]));
+    }
+
+    #[test]
+    fn error_responses_become_server_errors() {
+        let (mut client, _) = client(&[json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": -32601, "message": "Unhandled method" }
+        })]);
+        client.file_uri = Some("file:///tmp/a.c".to_string());
+
+        match client.document_symbols() {
+            Err(LspError::ServerError { code, message }) => {
+                assert_eq!((code, message.as_str()), (-32601, "Unhandled method"));
+            }
+            other => panic!("expected a server error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn requests_need_an_open_document() {
+        let (mut client, written) = client(&[]);
+        assert!(client.definition(0, 0).is_err());
+        assert!(written.messages().is_empty());
+    }
+
+    #[test]
+    fn call_reports_a_closed_connection() {
+        let (mut client, _) = client(&[]);
+        assert!(matches!(
+            client.call(&shutdown_request(1)),
+            Err(LspError::Exited(_))
+        ));
+    }
+
+    #[test]
+    fn call_gives_up_after_the_timeout() {
+        let (reader, _server) = io::pipe().unwrap();
+        let mut client = LspClient::new((Box::new(reader), Box::new(Written::default())));
+        client.set_timeout(Duration::from_millis(50));
+
+        assert!(matches!(
+            client.call(&shutdown_request(1)),
+            Err(LspError::Timeout(_))
+        ));
+    }
+}
@@ -0,0 +1,505 @@
+use crate::SymbolView;
+use crate::display::symbol_kind_name;
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+
+// What each command takes and does; the parser's usage errors and `help` both come from here.
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+}
+
+const fn spec(name: &'static str, usage: &'static str, summary: &'static str) -> CommandSpec {
+    CommandSpec {
+        name,
+        usage,
+        summary,
+    }
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    spec(
+        "help",
+        "help [<command>]",
+        "list the commands, or show how to use one",
+    ),
+    spec("def", "def [<line> <col>]", "go to definition at position"),
+    spec("decl", "decl <line> <col>", "go to declaration at position"),
+    spec(
+        "ref",
+        "ref [<line> <col>] [--no-decl]",
+        "list references to the symbol at position",
+    ),
+    spec(
+        "hover",
+        "hover <line> <col>",
+        "show hover information at position",
+    ),
+    spec(
+        "sym",
+        "sym [--tree] [<kind>]",
+        "list the document's symbols, optionally only one kind",
+    ),
+    spec(
+        "goto",
+        "goto <index>",
+        "go to definition of a location or symbol listed by def, ref or sym",
+    ),
+    spec("tokens", "tokens", "list semantic tokens"),
+    spec(
+        "hints",
+        "hints [<start-line> <end-line>]",
+        "list inlay hints in a range of lines",
+    ),
+    spec("fold", "fold", "list folding ranges"),
+    spec(
+        "select",
+        "select <line> <col>",
+        "show the selection ranges around position",
+    ),
+    spec("links", "links", "list document links"),
+    spec(
+        "fmt",
+        "fmt [--apply]",
+        "show formatting edits, or apply them to the file (printed with --stdin)",
+    ),
+    spec(
+        "action",
+        "action <line> <col>",
+        "list code actions at position",
+    ),
+    spec(
+        "complete",
+        "complete <line> <col>",
+        "list completions at position",
+    ),
+    spec(
+        "resolve",
+        "resolve <index>",
+        "resolve a completion listed by complete",
+    ),
+    spec("colors", "colors", "list document colors"),
+    spec(
+        "present",
+        "present <index>",
+        "list presentations of a color listed by colors",
+    ),
+    spec(
+        "prepare-rename",
+        "prepare-rename <line> <col>",
+        "check whether the symbol at position can be renamed",
+    ),
+    spec(
+        "rename",
+        "rename <line> <col> <new-name>",
+        "rename the symbol at position",
+    ),
+    spec(
+        "callers",
+        "callers <line> <col>",
+        "list calls to the function at position",
+    ),
+    spec(
+        "callees",
+        "callees <line> <col>",
+        "list calls made by the function at position",
+    ),
+    spec(
+        "open",
+        "open <path>",
+        "open another file and make it the active document",
+    ),
+    spec(
+        "reopen",
+        "reopen",
+        "close the active file and open it again from disk",
+    ),
+    spec(
+        "edit",
+        "edit <start-line> <start-col> <end-line> <end-col> [text]",
+        "replace a range of the document; \\n and \\t in text are escapes",
+    ),
+    spec(
+        "reload",
+        "reload",
+        "read the active file from disk again (alias: change)",
+    ),
+    spec(
+        "save",
+        "save [--text]",
+        "send willSave and didSave, with the text if asked",
+    ),
+    spec(
+        "raw",
+        "raw <json>",
+        "send a JSON-RPC request; the object needs a method",
+    ),
+    spec(
+        "config",
+        "config <json>",
+        "send settings to the server and answer its configuration requests with them",
+    ),
+    spec(
+        "server",
+        "server <command>",
+        "shut down the language server and start <command> in its place",
+    ),
+    spec("caps", "caps", "show the server's capabilities"),
+    spec("cancel", "cancel <id>", "cancel a pending request"),
+    spec("quit", "quit", "end the session"),
+];
+
+const ALIASES: &[(&str, &str)] = &[("change", "reload")];
+
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    let name = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, command)| command);
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+pub fn command_names() -> String {
+    COMMANDS
+        .iter()
+        .map(|spec| spec.name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Leaves the declaration itself out of `ref` results.
+const NO_DECLARATION: &str = "--no-decl";
+
+#[derive(Debug)]
+pub enum Command {
+    Help(Option<&'static CommandSpec>),
+    Definition(Option<(usize, usize)>),
+    Declaration(usize, usize),
+    References {
+        position: Option<(usize, usize)>,
+        include_declaration: bool,
+    },
+    Hover(usize, usize),
+    Symbols(SymbolView),
+    Goto(usize),
+    Tokens,
+    Hints(Option<(usize, usize)>),
+    Fold,
+    Select(usize, usize),
+    Links,
+    Format {
+        apply: bool,
+    },
+    Action(usize, usize),
+    Complete(usize, usize),
+    Resolve(usize),
+    Colors,
+    Present(usize),
+    PrepareRename(usize, usize),
+    Rename {
+        line: usize,
+        character: usize,
+        new_name: String,
+    },
+    Calls {
+        incoming: bool,
+        line: usize,
+        character: usize,
+    },
+    Open(PathBuf),
+    Reopen,
+    Edit {
+        start: (usize, usize),
+        end: (usize, usize),
+        text: String,
+    },
+    Reload,
+    Save {
+        text: bool,
+    },
+    Raw(Map<String, Value>),
+    Config(Map<String, Value>),
+    Server(String),
+    Caps,
+    Cancel(i32),
+    Quit,
+}
+
+fn parse_position(args: &str) -> Option<(usize, usize)> {
+    let mut words = args.split_whitespace();
+    let line = words.next()?.parse().ok()?;
+    let character = words.next()?.parse().ok()?;
+    words.next().is_none().then_some((line, character))
+}
+
+// A missing position is left for the caller to fill in.
+fn parse_optional_position(args: &str) -> Option<Option<(usize, usize)>> {
+    if args.trim().is_empty() {
+        Some(None)
+    } else {
+        parse_position(args).map(Some)
+    }
+}
+
+// Lets `edit` insert newlines and tabs from a single command line.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            unescaped.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+pub fn parse_command(input: &str) -> Result<Command, String> {
+    let input = input.trim();
+    let (name, args) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let spec = find_command(name).ok_or_else(|| format!("Unknown command: {input}"))?;
+    let usage = || format!("Usage: {}", spec.usage);
+    let position = || parse_position(args).ok_or_else(usage);
+    let no_args = |command: Command| {
+        if args.trim().is_empty() {
+            Ok(command)
+        } else {
+            Err(usage())
+        }
+    };
+
+    match spec.name {
+        "help" => match args.trim() {
+            "" => Ok(Command::Help(None)),
+            command => find_command(command)
+                .map(|spec| Command::Help(Some(spec)))
+                .ok_or_else(|| format!("Unknown command: {command}")),
+        },
+        "def" => parse_optional_position(args)
+            .map(Command::Definition)
+            .ok_or_else(usage),
+        "decl" => {
+            let (line, character) = position()?;
+            Ok(Command::Declaration(line, character))
+        }
+        "ref" => {
+            let (args, include_declaration) = match args.trim_end().strip_suffix(NO_DECLARATION) {
+                Some(args) => (args, false),
+                None => (args, true),
+            };
+            let position = parse_optional_position(args).ok_or_else(usage)?;
+            Ok(Command::References {
+                position,
+                include_declaration,
+            })
+        }
+        "hover" => {
+            let (line, character) = position()?;
+            Ok(Command::Hover(line, character))
+        }
+        "sym" => {
+            let mut view = SymbolView::default();
+            for word in args.split_whitespace() {
+                if word == "--tree" {
+                    view.tree = true;
+                } else if view.kind.is_some() {
+                    return Err(usage());
+                } else if (1..=26)
+                    .filter_map(symbol_kind_name)
+                    .any(|name| name.eq_ignore_ascii_case(word))
+                {
+                    view.kind = Some(word.to_string());
+                } else {
+                    return Err(format!("Unknown symbol kind: {word}"));
+                }
+            }
+            Ok(Command::Symbols(view))
+        }
+        "goto" => args.trim().parse().map(Command::Goto).map_err(|_| usage()),
+        "tokens" => no_args(Command::Tokens),
+        "hints" => parse_optional_position(args)
+            .map(Command::Hints)
+            .ok_or_else(usage),
+        "fold" => no_args(Command::Fold),
+        "select" => {
+            let (line, character) = position()?;
+            Ok(Command::Select(line, character))
+        }
+        "links" => no_args(Command::Links),
+        "fmt" => match args.trim() {
+            "" => Ok(Command::Format { apply: false }),
+            "--apply" => Ok(Command::Format { apply: true }),
+            _ => Err(usage()),
+        },
+        "action" => {
+            let (line, character) = position()?;
+            Ok(Command::Action(line, character))
+        }
+        "complete" => {
+            let (line, character) = position()?;
+            Ok(Command::Complete(line, character))
+        }
+        "resolve" => args
+            .trim()
+            .parse()
+            .map(Command::Resolve)
+            .map_err(|_| usage()),
+        "colors" => no_args(Command::Colors),
+        "present" => args
+            .trim()
+            .parse()
+            .map(Command::Present)
+            .map_err(|_| usage()),
+        "prepare-rename" => {
+            let (line, character) = position()?;
+            Ok(Command::PrepareRename(line, character))
+        }
+        "rename" => {
+            let ((line, character), new_name) = args
+                .trim()
+                .rsplit_once(char::is_whitespace)
+                .and_then(|(position, new_name)| Some((parse_position(position)?, new_name)))
+                .ok_or_else(usage)?;
+            Ok(Command::Rename {
+                line,
+                character,
+                new_name: new_name.to_string(),
+            })
+        }
+        "callers" | "callees" => {
+            let (line, character) = position()?;
+            Ok(Command::Calls {
+                incoming: spec.name == "callers",
+                line,
+                character,
+            })
+        }
+        "open" => match args.trim() {
+            "" => Err(usage()),
+            path => Ok(Command::Open(PathBuf::from(path))),
+        },
+        "reopen" => no_args(Command::Reopen),
+        "edit" => {
+            // The text starts after the single separator following the end column.
+            let mut rest = args;
+            let mut number = || {
+                let (word, after) = rest
+                    .trim_start()
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((rest.trim_start(), ""));
+                rest = after;
+                word.parse::<usize>().ok()
+            };
+            let (Some(start_line), Some(start_character), Some(end_line), Some(end_character)) =
+                (number(), number(), number(), number())
+            else {
+                return Err(usage());
+            };
+            Ok(Command::Edit {
+                start: (start_line, start_character),
+                end: (end_line, end_character),
+                text: unescape(rest),
+            })
+        }
+        "reload" => no_args(Command::Reload),
+        "save" => match args.trim() {
+            "" => Ok(Command::Save { text: false }),
+            "--text" => Ok(Command::Save { text: true }),
+            _ => Err(usage()),
+        },
+        "raw" => match serde_json::from_str::<Value>(args) {
+            Ok(Value::Object(message)) if message.get("method").is_some_and(Value::is_string) => {
+                Ok(Command::Raw(message))
+            }
+            Ok(_) => Err(format!(
+                "{}, where json is an object with a method",
+                usage()
+            )),
+            Err(e) => Err(format!("Invalid JSON: {e}")),
+        },
+        "config" => match serde_json::from_str::<Value>(args) {
+            Ok(Value::Object(settings)) => Ok(Command::Config(settings)),
+            Ok(_) => Err(format!("{}, where json is an object", usage())),
+            Err(e) => Err(format!("Invalid JSON: {e}")),
+        },
+        "server" => match args.trim() {
+            "" => Err(usage()),
+            command => Ok(Command::Server(command.to_string())),
+        },
+        "caps" => no_args(Command::Caps),
+        "cancel" => args
+            .trim()
+            .parse()
+            .map(Command::Cancel)
+            .map_err(|_| usage()),
+        "quit" => no_args(Command::Quit),
+        _ => unreachable!("every command in COMMANDS is parsed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_accepts_valid_lines() {
+        assert!(matches!(
+            parse_command("hover 3 7"),
+            Ok(Command::Hover(3, 7))
+        ));
+        assert!(matches!(
+            parse_command("  ref 1 2 --no-decl "),
+            Ok(Command::References {
+                position: Some((1, 2)),
+                include_declaration: false
+            })
+        ));
+        assert!(matches!(parse_command("change"), Ok(Command::Reload)));
+        assert!(matches!(
+            parse_command("rename 4 5 total"),
+            Ok(Command::Rename { line: 4, character: 5, new_name }) if new_name == "total"
+        ));
+    }
+
+    #[test]
+    fn parse_command_rejects_malformed_lines() {
+        assert_eq!(
+            parse_command("jump 1 2").unwrap_err(),
+            "Unknown command: jump 1 2"
+        );
+        assert_eq!(
+            parse_command("hover 3").unwrap_err(),
+            "Usage: hover <line> <col>"
+        );
+        assert_eq!(
+            parse_command("hover 3 x").unwrap_err(),
+            "Usage: hover <line> <col>"
+        );
+        assert_eq!(parse_command("fold 1").unwrap_err(), "Usage: fold");
+        assert!(parse_command("edit 0 0 0").is_err());
+        assert!(parse_command("raw {\"id\": 1}").is_err());
+    }
+
+    #[test]
+    fn parse_command_splits_edit_on_whitespace() {
+        let Ok(Command::Edit { start, end, text }) = parse_command("edit 1  2\t3   4 x  = 1;\\n")
+        else {
+            panic!("edit with extra whitespace did not parse");
+        };
+        assert_eq!((start, end), ((1, 2), (3, 4)));
+        assert_eq!(text, "x  = 1;\n");
+
+        let Ok(Command::Edit { text, .. }) = parse_command("edit 0 0 0 5") else {
+            panic!("edit without text did not parse");
+        };
+        assert_eq!(text, "");
+    }
+}
@@ -0,0 +1,39 @@
+use multitool::error::LspError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub command: Option<String>,
+    pub language: Option<String>,
+    pub no_color: Option<bool>,
+    pub server_arg: Vec<String>,
+    pub timeout: Option<u64>,
+    pub servers: HashMap<String, String>,
+}
+
+fn config_path() -> Option<PathBuf> {
+    let xdg = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from);
+    let home = env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"));
+
+    [xdg, home]
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.join("multitool").join("config.toml"))
+        .find(|path| path.is_file())
+}
+
+pub fn load() -> Result<FileConfig, LspError> {
+    let Some(path) = config_path() else {
+        return Ok(FileConfig::default());
+    };
+
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| LspError::io(format!("Failed to read config file {}", path.display()), e))?;
+    toml::from_str(&contents)
+        .map_err(|e| LspError::config(format!("Failed to parse config file {}", path.display()), e))
+}
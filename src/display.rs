@@ -1,235 +1,1887 @@
-use clap::Parser;
-use serde_json::{Value, to_string_pretty};
-use std::fs;
-use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::sync::{Arc, Mutex};
-use std::thread;
-
-pub fn format_range(range: &Value) -> Result<String, String> {
-    range.get("end").map_or_else(
-        || Err("Range end is missing".to_string()),
-        |end| {
-            range.get("start").map_or_else(
-                || Err("Range start is missing".to_string()),
-                |start| {
-                    Ok(format!(
-                        "{}:{}->{}:{}",
-                        start
-                            .get("line")
-                            .and_then(serde_json::Value::as_i64)
-                            .unwrap_or(-1),
-                        start
-                            .get("character")
-                            .and_then(serde_json::Value::as_i64)
-                            .unwrap_or(-1),
-                        end.get("line")
-                            .and_then(serde_json::Value::as_i64)
-                            .unwrap_or(-1),
-                        end.get("character")
-                            .and_then(serde_json::Value::as_i64)
-                            .unwrap_or(-1)
-                    ))
-                },
-            )
-        },
-    )
+use crate::edit::{PositionEncoding, position_to_offset};
+use crate::error::LspError;
+use crate::{ServerInfo, Session, SymbolView};
+use crate::{errln, output};
+use clap::ValueEnum;
+use serde_json::{Value, json, to_string_pretty};
+use std::io::Write;
+
+// Display output for a message is collected first and written out in one go.
+// Writing to a String can't fail.
+macro_rules! out {
+    ($out:expr, $($arg:tt)*) => {{
+        use std::fmt::Write as _;
+        let _ = writeln!($out, $($arg)*);
+    }};
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+    Jsonl,
 }
 
-fn display_definition(json_value: &Value) -> Result<(), String> {
-    if let Some(result) = json_value.get("result") {
-        if result.is_null() {
-            println!("No definition found.");
-        } else if let Some(results) = result.as_array() {
-            if results.is_empty() {
-                println!("No definition found.");
-            } else {
-                for item in results {
-                    if let Some(uri) = item.get("uri") {
-                        let uri = uri
-                            .as_str()
-                            .ok_or("Invalid URI")
-                            .map_err(|e| format!("Failed to format URI: {e}"))?;
-                        if let Some(range) = item.get("range") {
-                            match format_range(range) {
-                                Ok(range_str) => {
-                                    println!("{uri}\t{range_str}");
-                                }
-                                Err(e) => {
-                                    println!("Failed to format range: {e}");
-                                }
-                            }
-                        } else {
-                            println!("Definition found but range is missing.");
-                        }
-                    } else {
-                        println!("Definition found but URI is missing.");
-                    }
-                }
+#[derive(Clone, Copy, Debug)]
+pub struct Colors {
+    pub red: &'static str,
+    pub green: &'static str,
+    pub yellow: &'static str,
+    pub blue: &'static str,
+    pub magenta: &'static str,
+    pub cyan: &'static str,
+    pub bold: &'static str,
+    pub dim: &'static str,
+    pub normal: &'static str,
+}
+
+impl Colors {
+    pub const fn new(enabled: bool) -> Self {
+        if enabled {
+            Self {
+                red: "\x1b[31m",
+                green: "\x1b[32m",
+                yellow: "\x1b[33m",
+                blue: "\x1b[34m",
+                magenta: "\x1b[35m",
+                cyan: "\x1b[36m",
+                bold: "\x1b[1m",
+                dim: "\x1b[2m",
+                normal: "\x1b[0m",
+            }
+        } else {
+            Self {
+                red: "",
+                green: "",
+                yellow: "",
+                blue: "",
+                magenta: "",
+                cyan: "",
+                bold: "",
+                dim: "",
+                normal: "",
             }
         }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayOptions {
+    pub echo_commands: bool,
+    pub echo_responses: bool,
+    pub echo_raw: bool,
+    pub format: Format,
+    pub colors: Colors,
+    pub one_based: bool,
+    pub quiet: bool,
+    pub context: bool,
+    pub max_results: Option<usize>,
+    pub timings: bool,
+    pub raw_hover: bool,
+    pub diagnostics_only: bool,
+}
+
+pub fn format_position(position: &Value, one_based: bool) -> String {
+    let offset = i64::from(one_based);
+
+    format!(
+        "{}:{}",
+        position
+            .get("line")
+            .and_then(serde_json::Value::as_i64)
+            .map_or(-1, |n| n + offset),
+        position
+            .get("character")
+            .and_then(serde_json::Value::as_i64)
+            .map_or(-1, |n| n + offset)
+    )
+}
+
+pub fn format_range(range: &Value, one_based: bool) -> Result<String, LspError> {
+    let start = range
+        .get("start")
+        .ok_or_else(|| LspError::protocol("Range start is missing"))?;
+    let end = range
+        .get("end")
+        .ok_or_else(|| LspError::protocol("Range end is missing"))?;
+
+    Ok(format!(
+        "{}->{}",
+        format_position(start, one_based),
+        format_position(end, one_based)
+    ))
+}
+
+// Cuts a listing down to --max-results lines, saying how many were left out.
+fn display_listing(out: &mut String, listing: &str, options: &DisplayOptions) {
+    let total = listing.lines().count();
+    let shown = options.max_results.map_or(total, |max| max.min(total));
+    for line in listing.lines().take(shown) {
+        out!(out, "{line}");
+    }
+    if shown < total {
+        out!(out, "… and {} more", total - shown);
+    }
+}
+
+// Ends a listing with how many results it had; --quiet wants only the results.
+pub fn noun<'a>(count: usize, (singular, plural): (&'a str, &'a str)) -> &'a str {
+    if count == 1 { singular } else { plural }
+}
+
+fn display_count(
+    out: &mut String,
+    count: usize,
+    (singular, plural): (&str, &str),
+    note: Option<&str>,
+    options: &DisplayOptions,
+) {
+    if options.quiet {
+        return;
+    }
+
+    let noun = noun(count, (singular, plural));
+    match note {
+        Some(note) => out!(out, "{count} {noun} ({note})"),
+        None => out!(out, "{count} {noun}"),
+    }
+}
+
+// Listed locations are numbered so `goto` can pick one, and each is added to `targets`.
+fn display_locations(
+    out: &mut String,
+    json_value: &Value,
+    nouns: (&str, &str),
+    options: &DisplayOptions,
+    targets: &mut Vec<Value>,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    // A single Location is as valid as an array of them.
+    let locations = match result {
+        Value::Array(locations) => locations.as_slice(),
+        Value::Object(_) => std::slice::from_ref(result),
+        _ => &[],
+    };
+    if locations.is_empty() {
+        out!(out, "No {} found.", nouns.1);
         return Ok(());
     }
 
-    Err("No result found in JSON response".to_string())
-}
-
-fn display_references(json_value: &Value) -> Result<(), String> {
-    if let Some(result) = json_value.get("result") {
-        if result.is_null() {
-            println!("No references found.");
-        } else if let Some(results) = result.as_array() {
-            if results.is_empty() {
-                println!("No references found.");
-            } else {
-                for item in results {
-                    if let Some(uri) = item.get("uri") {
-                        let uri = uri
-                            .as_str()
-                            .ok_or("Invalid URI")
-                            .map_err(|e| format!("Failed to format URI: {e}"))?;
-                        if let Some(range) = item.get("range") {
-                            match format_range(range) {
-                                Ok(range_str) => {
-                                    println!("{uri}\t{range_str}");
-                                }
-                                Err(e) => {
-                                    println!("Failed to format range: {e}");
-                                }
-                            }
-                        } else {
-                            println!("Reference found but range is missing.");
-                        }
-                    } else {
-                        println!("Reference found but URI is missing.");
-                    }
-                }
+    let mut chars = nouns.0.chars();
+    let title: String = chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default();
+
+    let mut listing = String::new();
+    for item in locations {
+        let Some(uri) = item.get("uri") else {
+            out!(listing, "{title} found but URI is missing.");
+            continue;
+        };
+        let uri = uri
+            .as_str()
+            .ok_or_else(|| LspError::protocol("Invalid URI"))
+            .map_err(|e| LspError::protocol(format!("Failed to format URI: {e}")))?;
+
+        let Some(range) = item.get("range") else {
+            out!(listing, "{title} found but range is missing.");
+            continue;
+        };
+        match format_range(range, options.one_based) {
+            Ok(range_str) => {
+                out!(listing, "{}\t{uri}\t{range_str}", targets.len());
+                targets.push(json!({ "uri": uri, "range": range }));
             }
+            Err(e) => out!(listing, "Failed to format range: {e}"),
         }
-        return Ok(());
     }
+    display_listing(out, &listing, options);
+    display_count(out, targets.len(), nouns, None, options);
 
-    Err("No result found in JSON response".to_string())
+    Ok(())
 }
 
-fn display_symbols(json_value: &Value) -> Result<(), String> {
-    let symbols = json_value
-        .get("result")
-        .ok_or("No result found in JSON response")?
-        .as_array()
-        .ok_or("No symbols found.")?;
+pub const fn symbol_kind_name(kind: i64) -> Option<&'static str> {
+    Some(match kind {
+        1 => "File",
+        2 => "Module",
+        3 => "Namespace",
+        4 => "Package",
+        5 => "Class",
+        6 => "Method",
+        7 => "Property",
+        8 => "Field",
+        9 => "Constructor",
+        10 => "Enum",
+        11 => "Interface",
+        12 => "Function",
+        13 => "Variable",
+        14 => "Constant",
+        15 => "String",
+        16 => "Number",
+        17 => "Boolean",
+        18 => "Array",
+        19 => "Object",
+        20 => "Key",
+        21 => "Null",
+        22 => "EnumMember",
+        23 => "Struct",
+        24 => "Event",
+        25 => "Operator",
+        26 => "TypeParameter",
+        _ => return None,
+    })
+}
+
+pub const fn completion_kind_name(kind: i64) -> Option<&'static str> {
+    Some(match kind {
+        1 => "Text",
+        2 => "Method",
+        3 => "Function",
+        4 => "Constructor",
+        5 => "Field",
+        6 => "Variable",
+        7 => "Class",
+        8 => "Interface",
+        9 => "Module",
+        10 => "Property",
+        11 => "Unit",
+        12 => "Value",
+        13 => "Enum",
+        14 => "Keyword",
+        15 => "Snippet",
+        16 => "Color",
+        17 => "File",
+        18 => "Reference",
+        19 => "Folder",
+        20 => "EnumMember",
+        21 => "Constant",
+        22 => "Struct",
+        23 => "Event",
+        24 => "Operator",
+        25 => "TypeParameter",
+        _ => return None,
+    })
+}
 
-    if symbols.is_empty() {
-        return Err("No symbols found.".to_string());
+fn format_symbol_kind(symbol: &Value) -> String {
+    symbol
+        .get("kind")
+        .and_then(serde_json::Value::as_i64)
+        .map_or_else(
+            || "Unknown".to_string(),
+            |kind| symbol_kind_name(kind).map_or_else(|| kind.to_string(), str::to_string),
+        )
+}
+
+// The colors for severities and symbol kinds are all chosen here.
+fn severity_color(severity: i64, colors: Colors) -> &'static str {
+    match severity {
+        1 => colors.red,
+        2 => colors.yellow,
+        3 => colors.blue,
+        4 => colors.dim,
+        _ => colors.normal,
     }
+}
+
+fn symbol_kind_color(kind: i64, colors: Colors) -> &'static str {
+    match kind {
+        // Types
+        5 | 10 | 11 | 23 | 26 => colors.cyan,
+        // Functions
+        6 | 9 | 12 | 25 => colors.magenta,
+        // Variables
+        7 | 8 | 13 | 14 | 22 => colors.green,
+        _ => colors.normal,
+    }
+}
+
+fn colored_symbol_kind(symbol: &Value, colors: Colors) -> String {
+    let color = symbol
+        .get("kind")
+        .and_then(Value::as_i64)
+        .map_or(colors.normal, |kind| symbol_kind_color(kind, colors));
+    format!("{color}{}{}", format_symbol_kind(symbol), colors.normal)
+}
+
+fn symbol_matches(symbol: &Value, filter: Option<&str>) -> bool {
+    filter.is_none_or(|filter| format_symbol_kind(symbol).eq_ignore_ascii_case(filter))
+}
 
+fn display_symbol_information(
+    out: &mut String,
+    symbols: &[Value],
+    filter: Option<&str>,
+    options: &DisplayOptions,
+    targets: &mut Vec<Value>,
+) -> Result<usize, LspError> {
+    let mut count = 0;
     for symbol in symbols {
+        if !symbol_matches(symbol, filter) {
+            continue;
+        }
+
         let name = symbol
             .get("name")
-            .ok_or("Symbol found but name is missing.")?
+            .ok_or_else(|| LspError::protocol("Symbol found but name is missing."))?
             .as_str()
-            .ok_or("Invalid symbol name")?;
+            .ok_or_else(|| LspError::protocol("Invalid symbol name"))?;
 
         let location = symbol
             .get("location")
-            .ok_or("Symbol found but location is missing.")?;
+            .ok_or_else(|| LspError::protocol("Symbol found but location is missing."))?;
         let range = location
             .get("range")
-            .ok_or("Symbol location found but range is missing.")?;
+            .ok_or_else(|| LspError::protocol("Symbol location found but range is missing."))?;
 
         let uri = location
             .get("uri")
-            .ok_or("Symbol location found but URI is missing.")?
+            .ok_or_else(|| LspError::protocol("Symbol location found but URI is missing."))?
             .as_str()
-            .ok_or("Invalid symbol URI")?;
+            .ok_or_else(|| LspError::protocol("Invalid symbol URI"))?;
+
+        let range_str = format_range(range, options.one_based).map_err(|e| {
+            LspError::protocol(format!("Failed to format range for symbol '{name}': {e}"))
+        })?;
+        let kind = colored_symbol_kind(symbol, options.colors);
+        out!(out, "{}\t{uri}\t{range_str}\t{kind}\t{name}", targets.len());
+        targets.push(json!({ "uri": uri, "range": range }));
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn display_document_symbols(
+    out: &mut String,
+    symbols: &[Value],
+    uri: &str,
+    depth: usize,
+    filter: Option<&str>,
+    options: &DisplayOptions,
+    targets: &mut Vec<Value>,
+) -> Result<usize, LspError> {
+    let mut count = 0;
+    for symbol in symbols {
+        let name = symbol
+            .get("name")
+            .ok_or_else(|| LspError::protocol("Symbol found but name is missing."))?
+            .as_str()
+            .ok_or_else(|| LspError::protocol("Invalid symbol name"))?;
+
+        if symbol_matches(symbol, filter) {
+            let range = symbol
+                .get("selectionRange")
+                .ok_or_else(|| LspError::protocol("Symbol found but selectionRange is missing."))?;
+
+            let range_str = format_range(range, options.one_based).map_err(|e| {
+                LspError::protocol(format!("Failed to format range for symbol '{name}': {e}"))
+            })?;
+            let kind = colored_symbol_kind(symbol, options.colors);
+            let indent = "  ".repeat(depth);
+            out!(
+                out,
+                "{}\t{uri}\t{range_str}\t{kind}\t{indent}{name}",
+                targets.len()
+            );
+            targets.push(json!({ "uri": uri, "range": range }));
+            count += 1;
+        }
+
+        if let Some(children) = symbol.get("children").and_then(Value::as_array) {
+            count +=
+                display_document_symbols(out, children, uri, depth + 1, filter, options, targets)?;
+        }
+    }
+
+    Ok(count)
+}
+
+fn symbol_children(symbol: &Value) -> &[Value] {
+    symbol
+        .get("children")
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+// Keeps the parents of matching symbols so the tree still shows where they live.
+fn tree_matches(symbol: &Value, filter: Option<&str>) -> bool {
+    symbol_matches(symbol, filter)
+        || symbol_children(symbol)
+            .iter()
+            .any(|child| tree_matches(child, filter))
+}
+
+fn display_symbol_tree(
+    out: &mut String,
+    symbols: &[Value],
+    (uri, prefix): (&str, &str),
+    root: bool,
+    filter: Option<&str>,
+    options: &DisplayOptions,
+    targets: &mut Vec<Value>,
+) -> Result<usize, LspError> {
+    let visible: Vec<&Value> = symbols
+        .iter()
+        .filter(|symbol| tree_matches(symbol, filter))
+        .collect();
+
+    let mut count = 0;
+    for (index, symbol) in visible.iter().enumerate() {
+        let (connector, indent) = match (root, index + 1 == visible.len()) {
+            (true, _) => ("", ""),
+            (false, false) => ("├─ ", "│  "),
+            (false, true) => ("└─ ", "   "),
+        };
+
+        let name = symbol
+            .get("name")
+            .ok_or_else(|| LspError::protocol("Symbol found but name is missing."))?
+            .as_str()
+            .ok_or_else(|| LspError::protocol("Invalid symbol name"))?;
+
+        // SymbolInformation has no children, so it shows up as a list of roots.
+        let range = symbol
+            .get("selectionRange")
+            .or_else(|| {
+                symbol
+                    .get("location")
+                    .and_then(|location| location.get("range"))
+            })
+            .ok_or_else(|| LspError::protocol("Symbol found but range is missing."))?;
+        let range_str = format_range(range, options.one_based).map_err(|e| {
+            LspError::protocol(format!("Failed to format range for symbol '{name}': {e}"))
+        })?;
+        let kind = colored_symbol_kind(symbol, options.colors);
+        out!(
+            out,
+            "{}\t{prefix}{connector}{name} ({kind}) {range_str}",
+            targets.len()
+        );
+        let uri = symbol
+            .get("location")
+            .and_then(|location| location.get("uri"))
+            .and_then(Value::as_str)
+            .unwrap_or(uri);
+        targets.push(json!({ "uri": uri, "range": range }));
+        count += usize::from(symbol_matches(symbol, filter));
+
+        let prefix = format!("{prefix}{indent}");
+        count += display_symbol_tree(
+            out,
+            symbol_children(symbol),
+            (uri, &prefix),
+            false,
+            filter,
+            options,
+            targets,
+        )?;
+    }
+
+    Ok(count)
+}
+
+fn is_document_symbol(symbol: &Value) -> bool {
+    symbol.get("location").is_none() && symbol.get("selectionRange").is_some()
+}
+
+fn display_symbols(
+    out: &mut String,
+    json_value: &Value,
+    uri: &str,
+    view: &SymbolView,
+    options: &DisplayOptions,
+    targets: &mut Vec<Value>,
+) -> Result<(), LspError> {
+    let filter = view.kind.as_deref();
+
+    // Servers answer null for a file they can't make sense of, which is just as empty.
+    let symbols = match json_value.get("result") {
+        Some(Value::Array(symbols)) if !symbols.is_empty() => symbols,
+        None | Some(Value::Null | Value::Array(_)) => {
+            out!(out, "No symbols found.");
+            return Ok(());
+        }
+        Some(_) => {
+            return Err(LspError::protocol("Invalid documentSymbol result"));
+        }
+    };
+
+    let mut listing = String::new();
+    let count = if view.tree {
+        display_symbol_tree(
+            &mut listing,
+            symbols,
+            (uri, ""),
+            true,
+            filter,
+            options,
+            targets,
+        )?
+    } else if symbols.first().is_some_and(is_document_symbol) {
+        display_document_symbols(&mut listing, symbols, uri, 0, filter, options, targets)?
+    } else {
+        display_symbol_information(&mut listing, symbols, filter, options, targets)?
+    };
+    display_listing(out, &listing, options);
+
+    let note = filter.map(|filter| format!("filtered to {}", filter.to_lowercase()));
+    display_count(out, count, ("symbol", "symbols"), note.as_deref(), options);
+
+    Ok(())
+}
+
+fn completion_items(json_value: &Value) -> Vec<Value> {
+    let result = json_value.get("result");
+
+    // The result is either CompletionItem[] or a CompletionList wrapping them.
+    result
+        .and_then(Value::as_array)
+        .or_else(|| {
+            result
+                .and_then(|result| result.get("items"))
+                .and_then(Value::as_array)
+        })
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn display_completions(out: &mut String, items: &[Value]) {
+    if items.is_empty() {
+        out!(out, "No completions found.");
+        return;
+    }
+
+    for (index, item) in items.iter().enumerate() {
+        let label = item
+            .get("label")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let kind = item
+            .get("kind")
+            .and_then(Value::as_i64)
+            .and_then(completion_kind_name)
+            .unwrap_or_default();
+        let detail = item
+            .get("detail")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        out!(out, "{index}\t{kind}\t{label}\t{detail}");
+    }
+}
+
+fn display_resolved_completion(out: &mut String, json_value: &Value) -> Result<(), LspError> {
+    let item = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let label = item
+        .get("label")
+        .and_then(Value::as_str)
+        .ok_or_else(|| LspError::protocol("Completion item found but label is missing."))?;
+    out!(out, "{label}");
+
+    if let Some(detail) = item.get("detail").and_then(Value::as_str) {
+        out!(out, "{detail}");
+    }
+
+    // Documentation is either a plain string or MarkupContent.
+    let documentation = item.get("documentation").and_then(|documentation| {
+        documentation
+            .as_str()
+            .or_else(|| documentation.get("value").and_then(Value::as_str))
+    });
+    match documentation {
+        Some(documentation) => out!(out, "\n{documentation}"),
+        None => out!(out, "No documentation available."),
+    }
+
+    Ok(())
+}
+
+// Hover contents are MarkupContent, a MarkedString, or a list of MarkedStrings.
+fn hover_text(contents: &Value) -> Option<(String, bool)> {
+    match contents {
+        Value::String(text) => Some((text.clone(), true)),
+        Value::Array(items) => {
+            let texts: Vec<String> = items
+                .iter()
+                .filter_map(hover_text)
+                .map(|(text, _)| text)
+                .collect();
+            (!texts.is_empty()).then(|| (texts.join("\n\n"), true))
+        }
+        Value::Object(content) => {
+            let value = content.get("value").and_then(Value::as_str)?;
+            if let Some(language) = content.get("language").and_then(Value::as_str) {
+                return Some((format!("```{language}\n{value}\n```"), true));
+            }
+            let markdown = content.get("kind").and_then(Value::as_str) == Some("markdown");
+            Some((value.to_string(), markdown))
+        }
+        _ => None,
+    }
+}
+
+fn render_emphasis(line: &str, colors: Colors) -> String {
+    let parts: Vec<&str> = line.split("**").collect();
+    if parts.len().is_multiple_of(2) {
+        return line.to_string();
+    }
+
+    let mut rendered = String::with_capacity(line.len());
+    for (index, part) in parts.iter().enumerate() {
+        if index % 2 == 1 {
+            rendered.push_str(colors.bold);
+            rendered.push_str(part);
+            rendered.push_str(colors.normal);
+        } else {
+            rendered.push_str(part);
+        }
+    }
+    rendered
+}
+
+// Just enough markdown for a terminal: bold headings and emphasis, indented code, and no
+// runs of blank lines.
+fn render_markdown(markdown: &str, colors: Colors) -> String {
+    let mut rendered = String::new();
+    let mut in_code = false;
+    let mut blank = true;
+    for line in markdown.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+
+        let heading = line.trim_start_matches('#');
+        let line = if in_code {
+            format!("    {}", line.trim_end())
+        } else if heading.len() < line.len() && heading.starts_with(' ') {
+            format!("{}{}{}", colors.bold, heading.trim(), colors.normal)
+        } else {
+            render_emphasis(line.trim_end(), colors)
+        };
+        if line.trim().is_empty() {
+            if blank {
+                continue;
+            }
+            blank = true;
+        } else {
+            blank = false;
+        }
+        rendered.push_str(&line);
+        rendered.push('\n');
+    }
+
+    rendered.trim_end().to_string()
+}
+
+fn display_hover(
+    out: &mut String,
+    json_value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    match result.get("contents").and_then(hover_text) {
+        Some((text, true)) if !options.raw_hover => {
+            out!(out, "{}", render_markdown(&text, options.colors));
+        }
+        Some((text, _)) if !text.trim().is_empty() => out!(out, "{}", text.trim_end()),
+        _ => out!(out, "No hover information."),
+    }
+
+    Ok(())
+}
+
+fn display_colors(
+    out: &mut String,
+    colors: &[Value],
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    if colors.is_empty() {
+        out!(out, "No colors found.");
+        return Ok(());
+    }
+
+    for (index, information) in colors.iter().enumerate() {
+        let range = information
+            .get("range")
+            .ok_or_else(|| LspError::protocol("Color found but range is missing."))?;
+        let color = information
+            .get("color")
+            .ok_or_else(|| LspError::protocol("Color found but value is missing."))?;
+        let component = |name: &str| {
+            color
+                .get(name)
+                .and_then(Value::as_f64)
+                .ok_or_else(|| LspError::protocol(format!("Color found but {name} is missing.")))
+        };
+
+        out!(
+            out,
+            "{index}\t{}\trgba({}, {}, {}, {})",
+            format_range(range, options.one_based)?,
+            component("red")?,
+            component("green")?,
+            component("blue")?,
+            component("alpha")?
+        );
+    }
+
+    Ok(())
+}
+
+fn display_color_presentations(out: &mut String, json_value: &Value) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let presentations = match result.as_array() {
+        Some(presentations) if !presentations.is_empty() => presentations,
+        _ => {
+            out!(out, "No color presentations found.");
+            return Ok(());
+        }
+    };
+
+    for presentation in presentations {
+        let label = presentation
+            .get("label")
+            .and_then(Value::as_str)
+            .ok_or_else(|| LspError::protocol("Color presentation found but label is missing."))?;
+        out!(out, "{label}");
+    }
+
+    Ok(())
+}
+
+fn display_prepare_rename(
+    out: &mut String,
+    json_value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    // The result is a Range, a { range, placeholder } pair, or { defaultBehavior }.
+    if result.is_null() {
+        out!(out, "Cannot rename at this position.");
+    } else if result.get("defaultBehavior").is_some() {
+        out!(out, "Rename allowed");
+    } else if let Some(range) = result.get("range") {
+        let range_str = format_range(range, options.one_based)?;
+        let placeholder = result
+            .get("placeholder")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        out!(out, "Rename allowed\t{range_str}\t{placeholder}");
+    } else {
+        out!(
+            out,
+            "Rename allowed\t{}",
+            format_range(result, options.one_based)?
+        );
+    }
+
+    Ok(())
+}
+
+fn display_workspace_edit(
+    out: &mut String,
+    json_value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    // Edits arrive either keyed by URI in `changes` or as `documentChanges`.
+    let mut files: Vec<(&str, &[Value])> = Vec::new();
+    if let Some(changes) = result.get("changes").and_then(Value::as_object) {
+        for (uri, edits) in changes {
+            files.push((uri, edits.as_array().map_or(&[], Vec::as_slice)));
+        }
+    }
+    if let Some(document_changes) = result.get("documentChanges").and_then(Value::as_array) {
+        for change in document_changes {
+            let uri = change
+                .get("textDocument")
+                .and_then(|document| document.get("uri"))
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let edits = change.get("edits").and_then(Value::as_array);
+            files.push((uri, edits.map_or(&[], Vec::as_slice)));
+        }
+    }
+
+    let mut count = 0;
+    for (uri, edits) in &files {
+        for edit in *edits {
+            let range = edit
+                .get("range")
+                .ok_or_else(|| LspError::protocol("Text edit found but range is missing."))?;
+            let new_text = edit
+                .get("newText")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            out!(
+                out,
+                "{uri}\t{}\t{new_text}",
+                format_range(range, options.one_based)?
+            );
+            count += 1;
+        }
+    }
+
+    out!(
+        out,
+        "{count} {} in {} {}",
+        noun(count, ("edit", "edits")),
+        files.len(),
+        noun(files.len(), ("file", "files"))
+    );
+
+    Ok(())
+}
+
+fn display_code_actions(out: &mut String, json_value: &Value) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let actions = match result.as_array() {
+        Some(actions) if !actions.is_empty() => actions,
+        _ => {
+            out!(out, "No code actions available.");
+            return Ok(());
+        }
+    };
+
+    for action in actions {
+        let title = action
+            .get("title")
+            .and_then(Value::as_str)
+            .ok_or_else(|| LspError::protocol("Code action found but title is missing."))?;
+
+        match action.get("kind").and_then(Value::as_str) {
+            Some(kind) => out!(out, "{title}\t{kind}"),
+            None => out!(out, "{title}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn display_text_edits(
+    out: &mut String,
+    json_value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let edits = match result.as_array() {
+        Some(edits) if !edits.is_empty() => edits,
+        _ => {
+            out!(out, "No formatting changes.");
+            return Ok(());
+        }
+    };
+
+    for edit in edits {
+        let range = edit
+            .get("range")
+            .ok_or_else(|| LspError::protocol("Text edit found but range is missing."))?;
+        let new_text = edit
+            .get("newText")
+            .and_then(Value::as_str)
+            .ok_or_else(|| LspError::protocol("Text edit found but newText is missing."))?;
+
+        let range_str = format_range(range, options.one_based)?;
+        out!(out, "{range_str}\t{new_text:?}");
+    }
+
+    Ok(())
+}
+
+fn legend_names<'a>(legend: Option<&'a Value>, key: &str) -> Vec<&'a str> {
+    legend
+        .and_then(|legend| legend.get(key))
+        .and_then(Value::as_array)
+        .map(|names| names.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+fn display_semantic_tokens(
+    out: &mut String,
+    json_value: &Value,
+    legend: Option<&Value>,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = match json_value.get("result") {
+        None | Some(Value::Null) => {
+            out!(out, "No semantic tokens found.");
+            return Ok(());
+        }
+        Some(result) => result,
+    };
+
+    let data = result
+        .get("data")
+        .and_then(Value::as_array)
+        .ok_or_else(|| LspError::protocol("Semantic tokens result is missing 'data'"))?
+        .iter()
+        .map(|n| {
+            n.as_u64()
+                .ok_or_else(|| LspError::protocol("Semantic token data must be unsigned integers"))
+        })
+        .collect::<Result<Vec<u64>, _>>()?;
+
+    if data.is_empty() {
+        out!(out, "No semantic tokens found.");
+        return Ok(());
+    }
+
+    let token_types = legend_names(legend, "tokenTypes");
+    let token_modifiers = legend_names(legend, "tokenModifiers");
+    let offset = u64::from(options.one_based);
 
-        let range_str = format_range(range)
-            .map_err(|e| format!("Failed to format range for symbol '{name}': {e}"))?;
-        println!("{uri}\t{range_str}\t{name}");
+    // Each token is five integers, with positions relative to the previous token.
+    let (mut line, mut character) = (0, 0);
+    for token in data.chunks_exact(5) {
+        let (delta_line, delta_character, length, token_type, modifiers) =
+            (token[0], token[1], token[2], token[3], token[4]);
+
+        if delta_line > 0 {
+            line += delta_line;
+            character = delta_character;
+        } else {
+            character += delta_character;
+        }
+
+        let type_name = usize::try_from(token_type)
+            .ok()
+            .and_then(|index| token_types.get(index))
+            .map_or_else(|| token_type.to_string(), |name| (*name).to_string());
+        let modifier_names: Vec<String> = (0..64_usize)
+            .filter(|bit| modifiers & (1_u64 << bit) != 0)
+            .map(|bit| {
+                token_modifiers
+                    .get(bit)
+                    .map_or_else(|| bit.to_string(), |name| (*name).to_string())
+            })
+            .collect();
+
+        out!(
+            out,
+            "{}:{}\t{length}\t{type_name}\t{}",
+            line + offset,
+            character + offset,
+            modifier_names.join(",")
+        );
+    }
+
+    Ok(())
+}
+
+fn display_inlay_hints(
+    out: &mut String,
+    json_value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let hints = match result.as_array() {
+        Some(hints) if !hints.is_empty() => hints,
+        _ => {
+            out!(out, "No inlay hints found.");
+            return Ok(());
+        }
+    };
+
+    for hint in hints {
+        let position = hint
+            .get("position")
+            .ok_or_else(|| LspError::protocol("Inlay hint found but position is missing."))?;
+
+        // The label is either a string or a list of InlayHintLabelPart.
+        let label = match hint.get("label") {
+            Some(Value::String(label)) => label.clone(),
+            Some(Value::Array(parts)) => parts
+                .iter()
+                .filter_map(|part| part.get("value").and_then(Value::as_str))
+                .collect(),
+            _ => {
+                return Err(LspError::protocol("Inlay hint found but label is missing."));
+            }
+        };
+
+        out!(
+            out,
+            "{}\t{label}",
+            format_position(position, options.one_based)
+        );
+    }
+
+    Ok(())
+}
+
+fn display_folding_ranges(
+    out: &mut String,
+    json_value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let ranges = match result.as_array() {
+        Some(ranges) if !ranges.is_empty() => ranges,
+        _ => {
+            out!(out, "No folding ranges found.");
+            return Ok(());
+        }
+    };
+
+    let offset = i64::from(options.one_based);
+    for range in ranges {
+        let start = range
+            .get("startLine")
+            .and_then(Value::as_i64)
+            .ok_or_else(|| LspError::protocol("Folding range found but startLine is missing."))?;
+        let end = range
+            .get("endLine")
+            .and_then(Value::as_i64)
+            .ok_or_else(|| LspError::protocol("Folding range found but endLine is missing."))?;
+
+        match range.get("kind").and_then(Value::as_str) {
+            Some(kind) => out!(out, "{}-{}\t{kind}", start + offset, end + offset),
+            None => out!(out, "{}-{}", start + offset, end + offset),
+        }
+    }
+
+    Ok(())
+}
+
+fn display_document_links(
+    out: &mut String,
+    json_value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let links = match result.as_array() {
+        Some(links) if !links.is_empty() => links,
+        _ => {
+            out!(out, "No document links found.");
+            return Ok(());
+        }
+    };
+
+    for link in links {
+        let range = link
+            .get("range")
+            .ok_or_else(|| LspError::protocol("Document link found but range is missing."))?;
+        let range_str = format_range(range, options.one_based)?;
+
+        // Servers may leave the target out until the link is sent to documentLink/resolve.
+        match link.get("target").and_then(Value::as_str) {
+            Some(target) => out!(out, "{range_str}\t{target}"),
+            None => out!(out, "{range_str}\t(unresolved)"),
+        }
     }
 
     Ok(())
 }
 
+fn display_selection_ranges(
+    out: &mut String,
+    json_value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let selections = match result.as_array() {
+        Some(selections) if !selections.is_empty() => selections,
+        _ => {
+            out!(out, "No selection ranges found.");
+            return Ok(());
+        }
+    };
+
+    // Each entry is the innermost range, with every enclosing range chained through `parent`.
+    for selection in selections {
+        let mut depth = 0;
+        let mut current = Some(selection);
+        while let Some(selection) = current {
+            let range = selection
+                .get("range")
+                .ok_or_else(|| LspError::protocol("Selection range found but range is missing."))?;
+            out!(
+                out,
+                "{}{}",
+                "  ".repeat(depth),
+                format_range(range, options.one_based)?
+            );
+            current = selection.get("parent");
+            depth += 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn display_call_hierarchy_items(out: &mut String, json_value: &Value) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    if result.as_array().is_none_or(Vec::is_empty) {
+        out!(out, "No call hierarchy item found.");
+    }
+
+    Ok(())
+}
+
+fn display_calls(
+    out: &mut String,
+    json_value: &Value,
+    key: &str,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let calls = match result.as_array() {
+        Some(calls) if !calls.is_empty() => calls,
+        _ => {
+            let noun = if key == "from" { "callers" } else { "callees" };
+            out!(out, "No {noun} found.");
+            return Ok(());
+        }
+    };
+
+    for call in calls {
+        let item = call
+            .get(key)
+            .ok_or_else(|| LspError::protocol(format!("Call found but '{key}' is missing.")))?;
+        let name = item
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| LspError::protocol("Call hierarchy item found but name is missing."))?;
+        let uri = item
+            .get("uri")
+            .and_then(Value::as_str)
+            .ok_or_else(|| LspError::protocol("Call hierarchy item found but URI is missing."))?;
+        let range = item.get("selectionRange").ok_or_else(|| {
+            LspError::protocol("Call hierarchy item found but selectionRange is missing.")
+        })?;
+
+        let range_str = format_range(range, options.one_based)
+            .map_err(|e| LspError::protocol(format!("Failed to format range for '{name}': {e}")))?;
+        let from_ranges = call
+            .get("fromRanges")
+            .and_then(Value::as_array)
+            .map(|ranges| {
+                ranges
+                    .iter()
+                    .filter_map(|range| format_range(range, options.one_based).ok())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+        let kind = colored_symbol_kind(item, options.colors);
+        out!(out, "{uri}\t{range_str}\t{kind}\t{name}\t{from_ranges}");
+    }
+
+    Ok(())
+}
+
+fn command_uri(command: &Value) -> &str {
+    command
+        .get("params")
+        .and_then(|params| params.get("textDocument"))
+        .and_then(|document| document.get("uri"))
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+fn location_results(json_value: &Value) -> Result<Value, LspError> {
+    let result = json_value
+        .get("result")
+        .ok_or_else(|| LspError::protocol("No result found in JSON response"))?;
+
+    let locations = result
+        .as_array()
+        .map(|results| {
+            results
+                .iter()
+                .map(|item| {
+                    json!({
+                        "uri": item.get("uri"),
+                        "range": item.get("range"),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(Value::Array(locations))
+}
+
+fn symbol_json(symbol: &Value, uri: &str, filter: Option<&str>) -> Option<Value> {
+    if is_document_symbol(symbol) {
+        let children: Vec<Value> = symbol
+            .get("children")
+            .and_then(Value::as_array)
+            .map(|children| {
+                children
+                    .iter()
+                    .filter_map(|child| symbol_json(child, uri, filter))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Keep non-matching parents so that matching children stay reachable.
+        (symbol_matches(symbol, filter) || !children.is_empty()).then(|| {
+            json!({
+                "name": symbol.get("name"),
+                "kind": format_symbol_kind(symbol),
+                "uri": uri,
+                "range": symbol.get("selectionRange"),
+                "children": children,
+            })
+        })
+    } else {
+        let location = symbol.get("location");
+        symbol_matches(symbol, filter).then(|| {
+            json!({
+                "name": symbol.get("name"),
+                "kind": format_symbol_kind(symbol),
+                "uri": location.and_then(|l| l.get("uri")),
+                "range": location.and_then(|l| l.get("range")),
+            })
+        })
+    }
+}
+
+fn symbol_results(json_value: &Value, uri: &str, filter: Option<&str>) -> Result<Value, LspError> {
+    let symbols = match json_value.get("result") {
+        Some(Value::Array(symbols)) => symbols.as_slice(),
+        None | Some(Value::Null) => &[],
+        Some(_) => {
+            return Err(LspError::protocol("Invalid documentSymbol result"));
+        }
+    };
+
+    Ok(symbols
+        .iter()
+        .filter_map(|symbol| symbol_json(symbol, uri, filter))
+        .collect())
+}
+
+fn display_json(
+    out: &mut String,
+    method: &str,
+    command: &Value,
+    value: &Value,
+    symbol_filter: Option<&str>,
+) -> Result<(), LspError> {
+    let result = match method {
+        "textDocument/definition" | "textDocument/references" | "textDocument/declaration" => {
+            location_results(value)?
+        }
+        "textDocument/documentSymbol" => {
+            symbol_results(value, command_uri(command), symbol_filter)?
+        }
+        _ => value
+            .get("result")
+            .cloned()
+            .ok_or_else(|| LspError::protocol("No result found in JSON response"))?,
+    };
+
+    out!(out, "{}", json!({ "method": method, "result": result }));
+
+    Ok(())
+}
+
+// One compact object per line, so the output can be read as a stream.
+fn display_jsonl(out: &mut String, direction: &str, method: Option<&str>, body: &Value) {
+    out!(
+        out,
+        "{}",
+        json!({ "direction": direction, "method": method, "body": body })
+    );
+}
+
+fn display_error(out: &mut String, method: &str, error: &Value, options: &DisplayOptions) {
+    if options.format == Format::Json {
+        out!(out, "{}", json!({ "method": method, "error": error }));
+        return;
+    }
+
+    let error = LspError::server(error);
+
+    out!(
+        out,
+        "{}{error}{}",
+        options.colors.red,
+        options.colors.normal
+    );
+}
+
 fn display_message(
+    out: &mut String,
+    session: &Session,
+    server: &str,
     command: &Value,
     value: &Value,
-    echo_commands: bool,
-    echo_responses: bool,
-) -> Result<(), String> {
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
     let method = command
         .get("method")
         .and_then(|m| m.as_str())
         .unwrap_or("Unknown method");
 
-    if echo_commands {
+    let symbol_view = command
+        .get("id")
+        .and_then(Value::as_i64)
+        .and_then(|id| i32::try_from(id).ok())
+        .and_then(|id| {
+            session
+                .symbol_views
+                .lock()
+                .expect("Failed to lock symbol views")
+                .remove(&id)
+        })
+        .unwrap_or_default();
+
+    let jsonl = options.format == Format::Jsonl;
+    if jsonl {
+        if options.echo_commands {
+            display_jsonl(out, "-->", Some(method), command);
+        }
+        display_jsonl(out, "<--", Some(method), value);
+    }
+
+    if options.echo_commands && !jsonl {
         let command = to_string_pretty(command)
-            .map_err(|e| format!("Failed to format JSON: {e}"))
+            .map_err(|e| LspError::json("Failed to format JSON", e))
             .unwrap_or_else(|_| "Failed to format JSON".to_string());
-        println!("Command: {command}");
+        out!(out, "Command: {command}");
     }
 
-    if echo_responses {
+    if options.echo_responses && !jsonl {
         let response = to_string_pretty(&value)
-            .map_err(|e| format!("Failed to format JSON: {e}"))
+            .map_err(|e| LspError::json("Failed to format JSON", e))
             .unwrap_or_else(|_| "Failed to format JSON".to_string());
-        println!("Response: {response}",);
+        out!(out, "Response: {response}");
     }
 
-    match method {
+    if let Some(error) = value.get("error") {
+        if !jsonl {
+            display_error(out, method, error, options);
+        }
+        return Ok(());
+    }
+
+    if method == "initialize" {
+        session
+            .servers
+            .lock()
+            .expect("Failed to lock servers")
+            .insert(
+                server.to_string(),
+                ServerInfo::from_initialize(value.get("result")),
+            );
+    }
+
+    if method == "textDocument/documentColor" {
+        *session.colors.lock().expect("Failed to lock colors") = value
+            .get("result")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+    }
+
+    if method == "textDocument/completion" {
+        *session
+            .completions
+            .lock()
+            .expect("Failed to lock completions") = completion_items(value);
+    }
+
+    match options.format {
+        // Only jsonl streams the session's own bookkeeping.
+        Format::Json if is_session_request(method) => return Ok(()),
+        Format::Json => {
+            return display_json(out, method, command, value, symbol_view.kind.as_deref());
+        }
+        Format::Jsonl => return Ok(()),
+        Format::Text => {}
+    }
+
+    // The locations and symbols listed last are what `goto` picks from.
+    let mut targets = Vec::new();
+    let listed = match method {
         "textDocument/definition" => {
-            display_definition(value)?;
+            let nouns = ("definition", "definitions");
+            Some(display_locations(out, value, nouns, options, &mut targets))
         }
         "textDocument/references" => {
-            display_references(value)?;
+            let nouns = ("reference", "references");
+            Some(display_locations(out, value, nouns, options, &mut targets))
         }
-        "textDocument/documentSymbol" => {
-            display_symbols(value)?;
+        "textDocument/declaration" => {
+            let nouns = ("declaration", "declarations");
+            Some(display_locations(out, value, nouns, options, &mut targets))
+        }
+        "textDocument/documentSymbol" => Some(display_symbols(
+            out,
+            value,
+            command_uri(command),
+            &symbol_view,
+            options,
+            &mut targets,
+        )),
+        _ => None,
+    };
+    if let Some(listed) = listed {
+        *session.targets.lock().expect("Failed to lock targets") = targets;
+        return listed;
+    }
+
+    match method {
+        "initialize" | "shutdown" => {}
+        "textDocument/hover" => {
+            display_hover(out, value, options)?;
+        }
+        "textDocument/completion" => {
+            display_completions(out, &completion_items(value));
+        }
+        "textDocument/documentColor" => {
+            display_colors(
+                out,
+                &session.colors.lock().expect("Failed to lock colors"),
+                options,
+            )?;
+        }
+        "textDocument/colorPresentation" => {
+            display_color_presentations(out, value)?;
+        }
+        "completionItem/resolve" => {
+            display_resolved_completion(out, value)?;
+        }
+        "textDocument/prepareRename" => {
+            display_prepare_rename(out, value, options)?;
+        }
+        "textDocument/rename" => {
+            display_workspace_edit(out, value, options)?;
+        }
+        "textDocument/codeAction" => {
+            display_code_actions(out, value)?;
+        }
+        "textDocument/formatting" => {
+            display_text_edits(out, value, options)?;
+        }
+        "textDocument/semanticTokens/full" => {
+            let capabilities = session.server(server).capabilities;
+            let legend = capabilities
+                .as_ref()
+                .and_then(|capabilities| capabilities.get("semanticTokensProvider"))
+                .and_then(|provider| provider.get("legend"));
+            display_semantic_tokens(out, value, legend, options)?;
+        }
+        "textDocument/inlayHint" => {
+            display_inlay_hints(out, value, options)?;
+        }
+        "textDocument/foldingRange" => {
+            display_folding_ranges(out, value, options)?;
+        }
+        "textDocument/documentLink" => {
+            display_document_links(out, value, options)?;
+        }
+        "textDocument/selectionRange" => {
+            display_selection_ranges(out, value, options)?;
+        }
+        "textDocument/prepareCallHierarchy" => {
+            display_call_hierarchy_items(out, value)?;
+        }
+        "callHierarchy/incomingCalls" => {
+            display_calls(out, value, "from", options)?;
+        }
+        "callHierarchy/outgoingCalls" => {
+            display_calls(out, value, "to", options)?;
         }
         _ => {
             let command = to_string_pretty(command)
-                .map_err(|e| format!("Failed to format JSON: {e}"))
+                .map_err(|e| LspError::json("Failed to format JSON", e))
                 .unwrap_or_else(|_| "Failed to format JSON".to_string());
             let response = to_string_pretty(&value)
-                .map_err(|e| format!("Failed to format JSON: {e}"))
+                .map_err(|e| LspError::json("Failed to format JSON", e))
                 .unwrap_or_else(|_| "Failed to format JSON".to_string());
 
-            println!("Command: {command}");
-            println!("Response: {response}",);
+            out!(out, "Command: {command}");
+            out!(out, "Response: {response}");
+        }
+    }
+
+    Ok(())
+}
+
+fn severity_name(severity: i64) -> &'static str {
+    match severity {
+        1 => "error",
+        2 => "warning",
+        3 => "info",
+        4 => "hint",
+        _ => "unknown",
+    }
+}
+
+// The line a range starts on, and a marker under the range; later lines of a
+// multi-line range are not shown.
+fn source_context(
+    source: &str,
+    range: &Value,
+    encoding: PositionEncoding,
+) -> Option<(String, String)> {
+    let position = |key: &str| {
+        let position = range.get(key)?;
+        let line = usize::try_from(position.get("line")?.as_u64()?).ok()?;
+        let character = usize::try_from(position.get("character")?.as_u64()?).ok()?;
+        Some((line, character))
+    };
+    let (line, character) = position("start")?;
+    let (end_line, end_character) = position("end")?;
+
+    let text = source.lines().nth(line)?;
+    let line_start = position_to_offset(source, line, 0, encoding);
+    let start = position_to_offset(source, line, character, encoding);
+    let end = if end_line == line {
+        position_to_offset(source, line, end_character, encoding).max(start)
+    } else {
+        line_start + text.len()
+    };
+
+    // Keep tabs in the padding so the marker lines up however they're rendered.
+    let padding: String = source[line_start..start]
+        .chars()
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = source[start..end].chars().count().max(1);
+
+    Some((
+        text.to_string(),
+        format!("{padding}^{}", "~".repeat(width - 1)),
+    ))
+}
+
+fn display_diagnostics(
+    out: &mut String,
+    value: &Value,
+    session: &Session,
+    server: &str,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let params = value
+        .get("params")
+        .ok_or_else(|| LspError::protocol("No params found in notification"))?;
+    let uri = params
+        .get("uri")
+        .and_then(Value::as_str)
+        .ok_or_else(|| LspError::protocol("Diagnostics found but uri is missing."))?;
+    let diagnostics = params
+        .get("diagnostics")
+        .and_then(Value::as_array)
+        .ok_or_else(|| LspError::protocol("Diagnostics found but list is missing."))?;
+
+    if diagnostics.is_empty() {
+        out!(out, "No diagnostics for {uri}.");
+        return Ok(());
+    }
+
+    let source = if options.context {
+        let document_guard = session.document.lock().expect("Failed to lock document");
+        (document_guard.uri == uri).then(|| document_guard.source.clone())
+    } else {
+        None
+    };
+    let encoding = session.server(server).encoding;
+
+    out!(out, "{uri}");
+    for diagnostic in diagnostics {
+        let range = diagnostic
+            .get("range")
+            .ok_or_else(|| LspError::protocol("Diagnostic found but range is missing."))?;
+        let start = range
+            .get("start")
+            .ok_or_else(|| LspError::protocol("Diagnostic found but start is missing."))?;
+        let severity = diagnostic.get("severity").and_then(Value::as_i64);
+        let color = severity.map_or(options.colors.normal, |severity| {
+            severity_color(severity, options.colors)
+        });
+        let severity = severity.map_or("unknown", severity_name);
+        let message = diagnostic
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+
+        out!(
+            out,
+            "{}\t{color}{severity}{}\t{message}",
+            format_position(start, options.one_based),
+            options.colors.normal
+        );
+
+        if let Some((text, marker)) = source
+            .as_deref()
+            .and_then(|source| source_context(source, range, encoding))
+        {
+            out!(out, "    {text}");
+            out!(out, "    {marker}");
         }
     }
 
     Ok(())
 }
 
+fn display_progress(out: &mut String, value: &Value) -> Result<(), LspError> {
+    let progress = value
+        .get("params")
+        .and_then(|params| params.get("value"))
+        .ok_or_else(|| LspError::protocol("Progress notification is missing its value"))?;
+
+    let kind = progress
+        .get("kind")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let title = progress.get("title").and_then(Value::as_str);
+    let message = progress.get("message").and_then(Value::as_str);
+    let percentage = progress
+        .get("percentage")
+        .and_then(Value::as_u64)
+        .map(|percentage| format!("{percentage}%"));
+
+    let mut parts: Vec<&str> = [title, message].into_iter().flatten().collect();
+    if let Some(percentage) = &percentage {
+        parts.push(percentage);
+    }
+
+    if parts.is_empty() {
+        let status = if kind == "end" { "done" } else { kind };
+        out!(out, "[progress] {status}");
+    } else {
+        out!(out, "[progress] {}", parts.join(": "));
+    }
+
+    Ok(())
+}
+
+fn display_server_message(
+    out: &mut String,
+    value: &Value,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let params = value
+        .get("params")
+        .ok_or_else(|| LspError::protocol("No params found in notification"))?;
+    let message = params
+        .get("message")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+
+    let colors = options.colors;
+    let (label, color) = match params.get("type").and_then(Value::as_i64) {
+        Some(1) => ("Error", colors.red),
+        Some(2) => ("Warning", colors.yellow),
+        Some(3) => ("Info", colors.normal),
+        Some(4) => ("Log", colors.normal),
+        Some(5) => ("Debug", colors.normal),
+        _ => ("Unknown", colors.normal),
+    };
+
+    out!(out, "{color}[server {label}] {message}{}", colors.normal);
+
+    Ok(())
+}
+
+// Colors are for the terminal only.
+fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\x1b' {
+            chars.by_ref().find(|&ch| ch == 'm');
+        } else {
+            stripped.push(ch);
+        }
+    }
+    stripped
+}
+
+// `server` is the key of the server the message came from.
 pub fn display_json_rpc_message(
-    json_value: Option<Value>,
-    commands: &Arc<Mutex<Vec<Value>>>,
-    echo_commands: bool,
-    echo_responses: bool,
-) -> Result<(), String> {
-    if let Some(value) = json_value {
-        if let Some(id) = value.get("id") {
-            let commands_guard = commands.lock().expect("Failed to lock commands");
-            for command in commands_guard.iter() {
-                if command.get("id") == Some(id) {
-                    display_message(command, &value, echo_commands, echo_responses)?;
-                    return Ok(());
-                }
+    value: &Value,
+    session: &Session,
+    server: &str,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    let mut out = String::new();
+    let result = render_json_rpc_message(&mut out, value, session, server, options);
+    write_output(session, &out);
+
+    result
+}
+
+// Shows displayed output and copies it, without colors, to --output-file.
+pub fn write_output(session: &Session, out: &str) {
+    if out.is_empty() {
+        return;
+    }
+
+    output::print(out);
+    if let Some(file) = session
+        .output
+        .lock()
+        .expect("Failed to lock output file")
+        .as_mut()
+        && let Err(e) = file.write_all(strip_ansi(out).as_bytes())
+    {
+        errln!("Failed to write to output file: {e}");
+    }
+}
+
+// Requests the client makes to run the session itself rather than for a command.
+fn is_session_request(method: &str) -> bool {
+    matches!(method, "initialize" | "shutdown")
+}
+
+fn render_json_rpc_message(
+    out: &mut String,
+    value: &Value,
+    session: &Session,
+    server: &str,
+    options: &DisplayOptions,
+) -> Result<(), LspError> {
+    if value.get("method").is_none()
+        && let Some(id) = value
+            .get("id")
+            .and_then(Value::as_i64)
+            .and_then(|id| i32::try_from(id).ok())
+    {
+        // Each request gets exactly one response, so its entries are done with once matched.
+        let command = session
+            .commands
+            .lock()
+            .expect("Failed to lock commands")
+            .remove(&id);
+        let elapsed = session
+            .pending
+            .lock()
+            .expect("Failed to lock pending requests")
+            .remove(&id)
+            .map(|sent| sent.elapsed());
+        if let Some(command) = command {
+            let result = display_message(out, session, server, &command, value, options);
+            let method = command.get("method").and_then(Value::as_str).unwrap_or("");
+            if options.timings
+                && options.format == Format::Text
+                && !is_session_request(method)
+                && let Some(elapsed) = elapsed
+            {
+                out!(out, "(took {}ms)", elapsed.as_millis());
             }
+            return result;
         }
+    }
 
-        let pretty_json =
-            to_string_pretty(&value).map_err(|e| format!("Failed to format JSON: {e}"))?;
+    if value.get("method").and_then(Value::as_str) == Some("textDocument/publishDiagnostics")
+        && let Some(params) = value.get("params")
+        && let Some(uri) = params.get("uri").and_then(Value::as_str)
+    {
+        let diagnostics = params
+            .get("diagnostics")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        session
+            .diagnostics
+            .lock()
+            .expect("Failed to lock diagnostics")
+            .insert(uri.to_string(), diagnostics);
+    }
 
-        let normal = "\x1b[0m";
-        let green = "\x1b[32m";
+    // --check prints the diagnostics it waited for, even when quiet, and nothing else.
+    let hidden = if options.diagnostics_only {
+        value.get("method").and_then(Value::as_str) != Some("textDocument/publishDiagnostics")
+    } else {
+        options.quiet
+    };
+    if hidden && !options.echo_responses {
+        return Ok(());
+    }
 
-        println!("{green}{pretty_json}{normal}");
+    if options.format == Format::Jsonl {
+        display_jsonl(
+            out,
+            "<--",
+            value.get("method").and_then(Value::as_str),
+            value,
+        );
+        return Ok(());
+    }
 
-        Ok(())
-    } else {
-        Err("No JSON message received".to_string())
+    if options.format == Format::Json {
+        out!(out, "{value}");
+        return Ok(());
     }
+
+    match value.get("method").and_then(Value::as_str) {
+        Some("$/progress") => return display_progress(out, value),
+        Some("textDocument/publishDiagnostics") => {
+            return display_diagnostics(out, value, session, server, options);
+        }
+        // Requests the client answers on its own; the user didn't ask for them.
+        Some(
+            "window/workDoneProgress/create"
+            | "workspace/configuration"
+            | "client/registerCapability"
+            | "client/unregisterCapability",
+        ) => return Ok(()),
+        Some("window/showMessage" | "window/logMessage" | "window/showMessageRequest") => {
+            return display_server_message(out, value, options);
+        }
+        _ => {}
+    }
+
+    let pretty_json =
+        to_string_pretty(value).map_err(|e| LspError::json("Failed to format JSON", e))?;
+
+    out!(
+        out,
+        "{}{pretty_json}{}",
+        options.colors.green,
+        options.colors.normal
+    );
+
+    Ok(())
 }
@@ -0,0 +1,260 @@
+use crate::error::LspError;
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    Utf8,
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "utf-8" => Some(Self::Utf8),
+            "utf-16" => Some(Self::Utf16),
+            "utf-32" => Some(Self::Utf32),
+            _ => None,
+        }
+    }
+
+    const fn units(self, ch: char) -> usize {
+        match self {
+            Self::Utf8 => ch.len_utf8(),
+            Self::Utf16 => ch.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
+}
+
+pub fn position_to_offset(
+    source: &str,
+    line: usize,
+    character: usize,
+    encoding: PositionEncoding,
+) -> usize {
+    let mut line_start = 0;
+    for _ in 0..line {
+        match source[line_start..].find('\n') {
+            Some(newline) => line_start += newline + 1,
+            None => return source.len(),
+        }
+    }
+
+    // Characters are counted in code units of the encoding and clamped to the line end.
+    let mut units = 0;
+    for (index, ch) in source[line_start..].char_indices() {
+        if units >= character || ch == '\n' || ch == '\r' {
+            return line_start + index;
+        }
+        units += encoding.units(ch);
+    }
+
+    source.len()
+}
+
+pub fn offset_to_position(
+    source: &str,
+    offset: usize,
+    encoding: PositionEncoding,
+) -> Result<(usize, usize), LspError> {
+    if offset > source.len() {
+        return Err(LspError::Usage(format!(
+            "Offset {offset} is past the end of the document ({} bytes)",
+            source.len()
+        )));
+    }
+    if !source.is_char_boundary(offset) {
+        return Err(LspError::Usage(format!(
+            "Offset {offset} is inside a multi-byte character"
+        )));
+    }
+
+    let before = &source[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    let character = source[line_start..offset]
+        .chars()
+        .map(|ch| encoding.units(ch))
+        .sum();
+
+    Ok((line, character))
+}
+
+fn edit_offsets(
+    source: &str,
+    edit: &Value,
+    encoding: PositionEncoding,
+) -> Result<(usize, usize), LspError> {
+    let range = edit
+        .get("range")
+        .ok_or_else(|| LspError::protocol("Text edit is missing its range"))?;
+    let offset = |key: &str| -> Result<usize, LspError> {
+        let position = range
+            .get(key)
+            .ok_or_else(|| LspError::protocol(format!("Text edit range is missing '{key}'")))?;
+        let line = position.get("line").and_then(Value::as_u64);
+        let character = position.get("character").and_then(Value::as_u64);
+        match (line, character) {
+            (Some(line), Some(character)) => Ok(position_to_offset(
+                source,
+                usize::try_from(line).unwrap_or(usize::MAX),
+                usize::try_from(character).unwrap_or(usize::MAX),
+                encoding,
+            )),
+            _ => Err(LspError::protocol(format!(
+                "Text edit has an invalid '{key}' position"
+            ))),
+        }
+    };
+
+    let (start, end) = (offset("start")?, offset("end")?);
+    if start > end {
+        return Err(LspError::protocol("Text edit range ends before it starts"));
+    }
+
+    Ok((start, end))
+}
+
+pub fn apply_edits(
+    source: &str,
+    edits: &[Value],
+    encoding: PositionEncoding,
+) -> Result<String, LspError> {
+    let mut resolved = edits
+        .iter()
+        .map(|edit| {
+            let (start, end) = edit_offsets(source, edit, encoding)?;
+            let new_text = edit
+                .get("newText")
+                .and_then(Value::as_str)
+                .ok_or_else(|| LspError::protocol("Text edit is missing newText"))?;
+            Ok((start, end, new_text))
+        })
+        .collect::<Result<Vec<_>, LspError>>()?;
+    resolved.sort_by_key(|(start, end, _)| (*start, *end));
+
+    if resolved.windows(2).any(|pair| pair[1].0 < pair[0].1) {
+        return Err(LspError::protocol(
+            "Refusing to apply overlapping text edits",
+        ));
+    }
+
+    // Apply from the end so earlier offsets stay valid.
+    let mut result = source.to_string();
+    for (start, end, new_text) in resolved.into_iter().rev() {
+        result.replace_range(start..end, new_text);
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use PositionEncoding::{Utf8, Utf16, Utf32};
+
+    fn edit(start: (usize, usize), end: (usize, usize), new_text: &str) -> Value {
+        json!({
+            "range": {
+                "start": { "line": start.0, "character": start.1 },
+                "end": { "line": end.0, "character": end.1 }
+            },
+            "newText": new_text
+        })
+    }
+
+    // "😀" is four UTF-8 bytes, a UTF-16 surrogate pair and one UTF-32 code point.
+    const EMOJI: &str = "a😀b\nc";
+
+    #[test]
+    fn position_to_offset_counts_code_units_of_the_encoding() {
+        assert_eq!(position_to_offset(EMOJI, 0, 5, Utf8), 5);
+        assert_eq!(position_to_offset(EMOJI, 0, 3, Utf16), 5);
+        assert_eq!(position_to_offset(EMOJI, 0, 2, Utf32), 5);
+        assert_eq!(position_to_offset(EMOJI, 1, 0, Utf16), 7);
+    }
+
+    #[test]
+    fn offset_to_position_counts_code_units_of_the_encoding() {
+        assert_eq!(offset_to_position(EMOJI, 5, Utf8).unwrap(), (0, 5));
+        assert_eq!(offset_to_position(EMOJI, 5, Utf16).unwrap(), (0, 3));
+        assert_eq!(offset_to_position(EMOJI, 5, Utf32).unwrap(), (0, 2));
+        assert_eq!(offset_to_position(EMOJI, 8, Utf16).unwrap(), (1, 1));
+    }
+
+    #[test]
+    fn offset_to_position_rejects_offsets_it_cannot_place() {
+        assert!(matches!(
+            offset_to_position(EMOJI, 2, Utf16),
+            Err(LspError::Usage(_))
+        ));
+        assert!(matches!(
+            offset_to_position(EMOJI, 9, Utf16),
+            Err(LspError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn positions_stop_at_crlf_line_ends() {
+        let source = "ab\r\ncd\r\n";
+        assert_eq!(position_to_offset(source, 0, 10, Utf16), 2);
+        assert_eq!(position_to_offset(source, 1, 1, Utf16), 5);
+        assert_eq!(offset_to_position(source, 5, Utf16).unwrap(), (1, 1));
+        assert_eq!(
+            apply_edits(source, &[edit((0, 2), (0, 9), "!")], Utf16).unwrap(),
+            "ab!\r\ncd\r\n"
+        );
+    }
+
+    #[test]
+    fn positions_are_clamped_to_the_line_and_the_document() {
+        let source = "ab\ncd";
+        assert_eq!(position_to_offset(source, 0, 99, Utf16), 2);
+        assert_eq!(position_to_offset(source, 1, 99, Utf16), 5);
+        assert_eq!(position_to_offset(source, 7, 0, Utf16), 5);
+        assert_eq!(
+            apply_edits(source, &[edit((1, 2), (9, 9), "e")], Utf16).unwrap(),
+            "ab\ncde"
+        );
+    }
+
+    #[test]
+    fn edits_are_applied_in_document_order() {
+        let source = "one two three";
+        let edits = [
+            edit((0, 8), (0, 13), "3"),
+            edit((0, 0), (0, 3), "1"),
+            edit((0, 4), (0, 7), "2"),
+        ];
+        assert_eq!(apply_edits(source, &edits, Utf16).unwrap(), "1 2 3");
+    }
+
+    #[test]
+    fn edits_use_the_encoding_for_columns() {
+        assert_eq!(
+            apply_edits(EMOJI, &[edit((0, 3), (0, 4), "B")], Utf16).unwrap(),
+            "a😀B\nc"
+        );
+        assert_eq!(
+            apply_edits(EMOJI, &[edit((0, 2), (0, 3), "B")], Utf32).unwrap(),
+            "a😀B\nc"
+        );
+    }
+
+    #[test]
+    fn overlapping_edits_are_refused() {
+        let edits = [edit((0, 0), (0, 3), "x"), edit((0, 2), (0, 4), "y")];
+        assert!(matches!(
+            apply_edits("abcdef", &edits, Utf16),
+            Err(LspError::Protocol(_))
+        ));
+
+        // Touching edits don't overlap.
+        let edits = [edit((0, 2), (0, 4), "y"), edit((0, 0), (0, 2), "x")];
+        assert_eq!(apply_edits("abcdef", &edits, Utf16).unwrap(), "xyef");
+    }
+}
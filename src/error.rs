@@ -0,0 +1,112 @@
+use rustyline::error::ReadlineError;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum LspError {
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    Protocol(String),
+    Json {
+        context: String,
+        source: serde_json::Error,
+    },
+    ServerError {
+        code: i64,
+        message: String,
+    },
+    Timeout(String),
+    // The user asked for something that can't be done, such as a malformed command.
+    Usage(String),
+    // The language server went away or can't be used any more.
+    Exited(String),
+    Config {
+        context: String,
+        source: toml::de::Error,
+    },
+    Editor {
+        context: String,
+        source: ReadlineError,
+    },
+}
+
+impl LspError {
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        Self::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn json(context: impl Into<String>, source: serde_json::Error) -> Self {
+        Self::Json {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn protocol(message: impl Into<String>) -> Self {
+        Self::Protocol(message.into())
+    }
+
+    // The `error` member of a JSON-RPC error response.
+    pub fn server(error: &Value) -> Self {
+        Self::ServerError {
+            code: error
+                .get("code")
+                .and_then(Value::as_i64)
+                .unwrap_or_default(),
+            message: error
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or("Unknown error")
+                .to_string(),
+        }
+    }
+
+    pub fn config(context: impl Into<String>, source: toml::de::Error) -> Self {
+        Self::Config {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn editor(context: impl Into<String>, source: ReadlineError) -> Self {
+        Self::Editor {
+            context: context.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for LspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { context, source } => write!(f, "{context}: {source}"),
+            Self::Json { context, source } => write!(f, "{context} ({source})"),
+            Self::Config { context, source } => write!(f, "{context}: {source}"),
+            Self::Editor { context, source } => write!(f, "{context}: {source}"),
+            Self::Protocol(message)
+            | Self::Timeout(message)
+            | Self::Usage(message)
+            | Self::Exited(message) => write!(f, "{message}"),
+            Self::ServerError { code, message } => write!(f, "Error {code}: {message}"),
+        }
+    }
+}
+
+impl Error for LspError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Json { source, .. } => Some(source),
+            Self::Config { source, .. } => Some(source),
+            Self::Editor { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use multitool::error::LspError;
+use multitool::{errln, output};
+use rustyline::error::ReadlineError;
+use rustyline::{DefaultEditor, ExternalPrinter};
+use std::env;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+const PROMPT: &str = "> ";
+const HISTORY_FILE: &str = ".multitool_history";
+
+pub trait CommandSource {
+    fn read_command(&mut self) -> Result<Option<String>, LspError>;
+}
+
+pub struct LineSource<R>(pub R);
+
+impl<R: BufRead> CommandSource for LineSource<R> {
+    fn read_command(&mut self) -> Result<Option<String>, LspError> {
+        let mut buffer = String::new();
+        let bytes_read = self
+            .0
+            .read_line(&mut buffer)
+            .map_err(|e| LspError::io("Failed to read command", e))?;
+
+        if bytes_read == 0 {
+            return Ok(None); // EOF
+        }
+
+        Ok(Some(buffer))
+    }
+}
+
+pub struct EditorSource {
+    editor: DefaultEditor,
+    history: Option<PathBuf>,
+}
+
+impl EditorSource {
+    pub fn new() -> Result<Self, LspError> {
+        let mut editor =
+            DefaultEditor::new().map_err(|e| LspError::editor("Failed to start line editor", e))?;
+
+        let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(history) = &history {
+            // A missing history file just means this is the first session.
+            let _ = editor.load_history(history);
+        }
+
+        // Server threads print while the prompt is up; the editor redraws it below their text.
+        let mut printer = editor
+            .create_external_printer()
+            .map_err(|e| LspError::editor("Failed to start line editor", e))?;
+        output::set_printer(Box::new(move |text| {
+            let _ = printer.print(text);
+        }));
+
+        Ok(Self { editor, history })
+    }
+}
+
+impl CommandSource for EditorSource {
+    fn read_command(&mut self) -> Result<Option<String>, LspError> {
+        match self.editor.readline(PROMPT) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = self.editor.add_history_entry(line.as_str());
+                    if let Some(history) = &self.history
+                        && let Err(e) = self.editor.append_history(history)
+                    {
+                        errln!("Failed to save history: {e}");
+                    }
+                }
+                Ok(Some(line))
+            }
+            Err(ReadlineError::Interrupted) => Ok(Some(String::new())),
+            Err(ReadlineError::Eof) => Ok(None),
+            Err(e) => Err(LspError::editor("Failed to read command", e)),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+pub mod client;
+pub mod command;
+pub mod display;
+pub mod edit;
+pub mod error;
+pub mod output;
+pub mod request;
+pub mod transport;
+
+use clap::ValueEnum;
+use display::Format;
+use edit::PositionEncoding;
+use error::LspError;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{self, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub fn path_to_uri(path: &Path) -> String {
+    // Working on the raw bytes means a path that isn't valid UTF-8 is escaped, not a panic.
+    let path_bytes = path.as_os_str().as_encoded_bytes();
+
+    let mut uri = String::from("file://");
+    if !path_bytes.starts_with(b"/") {
+        uri.push('/');
+    }
+    for &byte in path_bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(char::from(byte));
+            }
+            _ => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    uri
+}
+
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut index = 0;
+    while index < encoded.len() {
+        if encoded[index] == b'%' {
+            let hex = std::str::from_utf8(encoded.get(index + 1..index + 3)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            bytes.push(encoded[index]);
+            index += 1;
+        }
+    }
+
+    bytes_to_path(bytes)
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    // Unix paths are arbitrary bytes, so the ones `path_to_uri` escaped come back unchanged.
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+pub fn process_file(file_path: &Path) -> Result<(String, String), LspError> {
+    let current_file = fs::canonicalize(file_path)
+        .map_err(|e| LspError::io("Error: Unable to canonicalize file path", e))?;
+    let file_uri_str = path_to_uri(&current_file);
+
+    let source =
+        fs::read_to_string(file_path).map_err(|e| LspError::io("Error: Unable to read file", e))?;
+
+    Ok((file_uri_str, source))
+}
+
+pub fn process_stdin(file_path: &Path) -> Result<(String, String), LspError> {
+    // The file may not exist on disk, so the path is made absolute without resolving it.
+    let current_file = path::absolute(file_path)
+        .map_err(|e| LspError::io("Error: Unable to resolve file path", e))?;
+    let file_uri_str = path_to_uri(&current_file);
+
+    let mut source = String::new();
+    io::stdin()
+        .read_to_string(&mut source)
+        .map_err(|e| LspError::io("Error: Unable to read source from stdin", e))?;
+
+    Ok((file_uri_str, source))
+}
+
+pub struct Document {
+    pub uri: String,
+    pub source: String,
+    pub language_id: String,
+    pub version: i32,
+    // Read from stdin, so the path in its URI isn't where its text came from.
+    pub from_stdin: bool,
+}
+
+pub struct Config {
+    pub language: Option<String>,
+    pub quiet: bool,
+    pub interactive: bool,
+    pub offsets: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum FollowUp {
+    Request(&'static str),
+    ApplyEdits {
+        uri: String,
+    },
+    Rename {
+        uri: String,
+        line: usize,
+        character: usize,
+        new_name: String,
+    },
+}
+
+// How one `sym` command wants its results shown.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolView {
+    pub kind: Option<String>,
+    pub tree: bool,
+}
+
+// How edits are sent to the server; clients can't declare this, so it is our choice alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SyncKind {
+    None,
+    #[default]
+    Full,
+    Incremental,
+}
+
+pub struct Count(pub i32);
+
+impl Count {
+    pub fn inc(&mut self) -> i32 {
+        self.0 += 1;
+        self.0
+    }
+}
+
+// What a server said about itself when it initialized.
+#[derive(Clone, Default)]
+pub struct ServerInfo {
+    pub capabilities: Option<Value>,
+    pub encoding: PositionEncoding,
+}
+
+impl ServerInfo {
+    pub fn from_initialize(result: Option<&Value>) -> Self {
+        let capabilities = result
+            .and_then(|result| result.get("capabilities"))
+            .cloned();
+
+        // Servers that don't answer with an encoding are using the UTF-16 default.
+        let encoding = capabilities
+            .as_ref()
+            .and_then(|capabilities| capabilities.get("positionEncoding"))
+            .and_then(Value::as_str)
+            .and_then(PositionEncoding::from_name)
+            .unwrap_or_default();
+
+        Self {
+            capabilities,
+            encoding,
+        }
+    }
+}
+
+pub struct Session {
+    pub count: Mutex<Count>,
+    pub document: Mutex<Document>,
+    pub commands: Mutex<HashMap<i32, Value>>,
+    pub pending: Mutex<HashMap<i32, Instant>>,
+    pub follow_ups: Mutex<HashMap<i32, FollowUp>>,
+    // Keyed by the server's command line or address.
+    pub servers: Mutex<HashMap<String, ServerInfo>>,
+    // Which server each language's documents are sent to, kept up to date by the caller.
+    pub routes: Mutex<HashMap<String, String>>,
+    pub diagnostics: Mutex<HashMap<String, Vec<Value>>>,
+    pub completions: Mutex<Vec<Value>>,
+    pub colors: Mutex<Vec<Value>>,
+    pub settings: Mutex<Value>,
+    pub targets: Mutex<Vec<Value>>,
+    pub symbol_views: Mutex<HashMap<i32, SymbolView>>,
+    pub output: Mutex<Option<fs::File>>,
+    pub server_switch: Mutex<Option<String>>,
+    pub sync_kind: SyncKind,
+    pub format: Format,
+}
+
+impl Session {
+    pub fn new(document: Document) -> Self {
+        Self {
+            count: Mutex::new(Count(0)),
+            document: Mutex::new(document),
+            commands: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            follow_ups: Mutex::new(HashMap::new()),
+            servers: Mutex::new(HashMap::new()),
+            routes: Mutex::new(HashMap::new()),
+            diagnostics: Mutex::new(HashMap::new()),
+            completions: Mutex::new(Vec::new()),
+            colors: Mutex::new(Vec::new()),
+            settings: Mutex::new(Value::Object(serde_json::Map::new())),
+            targets: Mutex::new(Vec::new()),
+            symbol_views: Mutex::new(HashMap::new()),
+            output: Mutex::new(None),
+            server_switch: Mutex::new(None),
+            sync_kind: SyncKind::default(),
+            format: Format::Text,
+        }
+    }
+
+    pub fn server(&self, key: &str) -> ServerInfo {
+        self.servers
+            .lock()
+            .expect("Failed to lock servers")
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // The server that documents in `language_id` are sent to.
+    pub fn server_for(&self, language_id: &str) -> ServerInfo {
+        let key = self
+            .routes
+            .lock()
+            .expect("Failed to lock routes")
+            .get(language_id)
+            .cloned();
+        key.map(|key| self.server(&key)).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_to_uri_escapes_spaces() {
+        assert_eq!(
+            path_to_uri(Path::new("/home/me/my file.c")),
+            "file:///home/me/my%20file.c"
+        );
+    }
+
+    #[test]
+    fn path_to_uri_escapes_non_ascii_as_utf8() {
+        assert_eq!(
+            path_to_uri(Path::new("/tmp/café.c")),
+            "file:///tmp/caf%C3%A9.c"
+        );
+    }
+
+    #[test]
+    fn path_to_uri_adds_the_leading_slash() {
+        assert_eq!(path_to_uri(Path::new("/tmp/a.c")), "file:///tmp/a.c");
+        assert_eq!(path_to_uri(Path::new("C:/src/a.c")), "file:///C%3A/src/a.c");
+    }
+
+    #[test]
+    fn uri_to_path_round_trips() {
+        for path in [
+            "/tmp/a.c",
+            "/home/me/my file.c",
+            "/tmp/café.c",
+            "/tmp/100%.c",
+        ] {
+            assert_eq!(
+                uri_to_path(&path_to_uri(Path::new(path))),
+                Some(PathBuf::from(path))
+            );
+        }
+        assert_eq!(uri_to_path("https://example.com/a.c"), None);
+
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = Path::new(OsStr::from_bytes(b"/tmp/\xFF.c"));
+            assert_eq!(path_to_uri(path), "file:///tmp/%FF.c");
+            assert_eq!(uri_to_path(&path_to_uri(path)).as_deref(), Some(path));
+        }
+    }
+
+    #[test]
+    fn servers_are_looked_up_by_language() {
+        let session = Session::new(Document {
+            uri: "file:///tmp/a.c".to_string(),
+            source: String::new(),
+            language_id: "c".to_string(),
+            version: 1,
+            from_stdin: false,
+        });
+        let utf8 = serde_json::json!({ "capabilities": { "positionEncoding": "utf-8" } });
+        session.servers.lock().unwrap().extend([
+            (
+                "clangd".to_string(),
+                ServerInfo::from_initialize(Some(&utf8)),
+            ),
+            ("pylsp".to_string(), ServerInfo::from_initialize(None)),
+        ]);
+        session.routes.lock().unwrap().extend([
+            ("c".to_string(), "clangd".to_string()),
+            ("python".to_string(), "pylsp".to_string()),
+        ]);
+
+        assert_eq!(session.server_for("c").encoding, PositionEncoding::Utf8);
+        assert_eq!(
+            session.server_for("python").encoding,
+            PositionEncoding::Utf16
+        );
+        assert!(session.server_for("rust").capabilities.is_none());
+    }
+}
@@ -1,17 +1,28 @@
-mod request;
-mod display;
+mod config;
+mod input;
 
 use clap::Parser;
-use serde_json::{Value, to_string_pretty};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::fs;
-use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitCode, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use request::*;
-use display::*;
+use input::*;
+use multitool::client::{LspClient, Messages};
+use multitool::command::{Command, parse_command};
+use multitool::display::*;
+use multitool::error::LspError;
+use multitool::request::*;
+use multitool::transport::*;
+use multitool::{Config, Document, Session, SyncKind, process_file, process_stdin};
+use multitool::{errln, outln, output};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -20,9 +31,33 @@ use display::*;
     about = "A language server client."
 )]
 struct Args {
-    /// The command to execute for the language server
-    #[clap(short, long, default_value = "clangd")]
-    command: String,
+    /// The file to open (asked for when omitted and stdin is a terminal)
+    #[clap(conflicts_with = "stdin")]
+    file: Option<PathBuf>,
+
+    /// The command to execute for the language server [default: clangd]
+    #[clap(short, long)]
+    command: Option<String>,
+
+    /// Connect to a language server listening on host:port instead of spawning one
+    #[clap(long)]
+    socket: Option<String>,
+
+    /// An argument to pass to the language server (may be repeated)
+    #[clap(long = "server-arg", allow_hyphen_values = true)]
+    server_args: Vec<String>,
+
+    /// The directory to start the language server in [default: the current directory]
+    #[clap(long)]
+    working_dir: Option<PathBuf>,
+
+    /// An environment variable to set for the language server, as KEY=VALUE (may be repeated)
+    #[clap(long = "server-env", value_parser = parse_env)]
+    server_env: Vec<(String, String)>,
+
+    /// Use a different server for one language, as LANGUAGE=COMMAND (may be repeated)
+    #[clap(long = "server", value_parser = parse_route)]
+    servers: Vec<(String, String)>,
 
     /// Print stderr from the language server
     #[clap(long)]
@@ -36,241 +71,1263 @@ struct Args {
     #[clap(long)]
     echo_responses: bool,
 
-    /// Turn on all echo options
+    /// Print the exact bytes of every frame sent and received, headers included
+    #[clap(long)]
+    echo_raw: bool,
+
+    /// Turn on the stderr, command and response echo options
     #[clap(short, long)]
     debug: bool,
+
+    /// Output format for command results (jsonl streams every message, one per line)
+    #[clap(long, value_enum, default_value = "text")]
+    format: Format,
+
+    /// Accept a byte offset into the document, as @<offset>, wherever a command takes <line> <col>
+    #[clap(long)]
+    offsets: bool,
+
+    /// Print line and column numbers starting from 1 instead of 0
+    #[clap(long)]
+    one_based: bool,
+
+    /// Print only the results of commands, without prompts or notifications
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Print at most this many references or symbols, then say how many were left out
+    #[clap(long)]
+    max_results: Option<usize>,
+
+    /// Print how long each request took to get its response
+    #[clap(long)]
+    timings: bool,
+
+    /// Print hover text exactly as the server sent it, without rendering markdown
+    #[clap(long)]
+    raw_hover: bool,
+
+    /// Show the source line under each diagnostic, with the range marked
+    #[clap(long)]
+    context: bool,
+
+    /// Disable colored output
+    #[clap(long, overrides_with = "color")]
+    no_color: bool,
+
+    /// Keep colored output even when the config file sets no_color
+    #[clap(long, overrides_with = "no_color")]
+    color: bool,
+
+    /// Give up waiting for a response after this many milliseconds
+    #[clap(long)]
+    timeout: Option<u64>,
+
+    /// Give up if the server hasn't answered initialize after this many seconds
+    #[clap(long, visible_alias = "command-timeout", default_value_t = 30)]
+    init_timeout: u64,
+
+    /// Write all JSON-RPC traffic to this file
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+
+    /// Also write displayed results to this file
+    #[clap(long)]
+    output_file: Option<PathBuf>,
+
+    /// Write every frame, pretty-printed with its method and id, to stderr or to --trace=FILE
+    #[clap(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = TRACE_STDERR
+    )]
+    trace: Option<PathBuf>,
+
+    /// A JSON file whose object is sent to the server as initializationOptions
+    #[clap(long)]
+    initialization_options: Option<PathBuf>,
+
+    /// The workspace root (defaults to the directory containing the file)
+    #[clap(long)]
+    root: Option<PathBuf>,
+
+    /// Read commands from a file, one per line, instead of stdin
+    #[clap(long)]
+    script: Option<PathBuf>,
+
+    /// Read the document text from stdin instead of from the file
+    #[clap(long, requires = "uri")]
+    stdin: bool,
+
+    /// The file name to use for the document read with --stdin
+    #[clap(long, requires = "stdin")]
+    uri: Option<PathBuf>,
+
+    /// How edits are sent: not at all, as the whole text, or as the changed range
+    #[clap(long, value_enum, default_value = "full")]
+    sync_kind: SyncKind,
+
+    /// Restart the language server if it exits during the session
+    #[clap(long)]
+    restart: bool,
+
+    /// Print the file's diagnostics and exit, with status 1 if any are errors
+    #[clap(long)]
+    check: bool,
+
+    /// The languageId to send to the server (detected from the file extension by default)
+    #[clap(short, long)]
+    language: Option<String>,
 }
 
-fn process_file(file_path: &PathBuf) -> Result<(String, String), String> {
-    let current_file = fs::canonicalize(file_path)
-        .map_err(|_| "Error: Unable to canonicalize file path".to_string())?;
-    let current_file_str = current_file
-        .to_str()
-        .expect("Error: Unable to convert path to string");
-    let file_uri_str = format!("file://{current_file_str}");
+const DEFAULT_COMMAND: &str = "clangd";
 
-    let source =
-        fs::read_to_string(file_path).map_err(|_| "Error: Unable to read file".to_string())?;
+impl Args {
+    // Command-line flags win over the config file.
+    fn merge(&mut self, file: config::FileConfig) {
+        self.command = self.command.take().or(file.command);
+        self.language = self.language.take().or(file.language);
+        if !self.color {
+            self.no_color |= file.no_color.unwrap_or_default();
+        }
+        if self.server_args.is_empty() {
+            self.server_args = file.server_arg;
+        }
+        self.timeout = self.timeout.or(file.timeout);
 
-    Ok((file_uri_str, source))
+        for (language, command) in file.servers {
+            if !self
+                .servers
+                .iter()
+                .any(|(existing, _)| *existing == language)
+            {
+                self.servers.push((language, command));
+            }
+        }
+    }
 }
 
-struct Count(i32);
+fn parse_route(route: &str) -> Result<(String, String), String> {
+    match route.split_once('=') {
+        Some((language, command)) if !language.is_empty() && !command.trim().is_empty() => {
+            Ok((language.to_string(), command.trim().to_string()))
+        }
+        _ => Err(format!("Expected LANGUAGE=COMMAND, got '{route}'")),
+    }
+}
 
-impl Count {
-    fn inc(&mut self) -> i32 {
-        self.0 += 1;
-        self.0
+fn parse_env(variable: &str) -> Result<(String, String), String> {
+    match variable.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected KEY=VALUE, got '{variable}'")),
     }
 }
 
-fn start_server_process(command: &str) -> Result<std::process::Child, String> {
-    Command::new(command)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to start server: {e}"))
+// Where `--trace` writes when it isn't given a file.
+const TRACE_STDERR: &str = "-";
+
+struct LogSink {
+    writer: Box<dyn Write + Send>,
+    trace: bool,
 }
 
-fn handle_stdin(
-    mut stdin: std::process::ChildStdin,
-    count: &Arc<Mutex<Count>>,
-    file_uri: &str,
-    source: &str,
-    commands: &Arc<Mutex<Vec<Value>>>,
-) -> Result<(), String> {
-    {
-        let mut count_guard = count.lock().expect("Failed to lock count");
-        stdin
-            .write_all(&initialize_request(count_guard.inc()))
-            .map_err(|e| format!("Failed to write initialize request: {e}"))?;
+// Both server threads write here, so every sink sits behind the one lock and
+// a frame is always written whole.
+struct TrafficLog {
+    sinks: Mutex<Vec<LogSink>>,
+    start: Instant,
+}
+
+impl TrafficLog {
+    fn create(
+        log_file: Option<&PathBuf>,
+        trace: Option<&PathBuf>,
+    ) -> Result<Option<Self>, LspError> {
+        let open = |path: &PathBuf| {
+            fs::File::create(path).map_err(|e| {
+                LspError::io(format!("Failed to create log file {}", path.display()), e)
+            })
+        };
+
+        let mut sinks = Vec::new();
+        if let Some(path) = log_file {
+            sinks.push(LogSink {
+                writer: Box::new(open(path)?),
+                trace: false,
+            });
+        }
+        if let Some(path) = trace {
+            let writer: Box<dyn Write + Send> = if path.as_os_str() == TRACE_STDERR {
+                Box::new(output::Stderr)
+            } else {
+                Box::new(open(path)?)
+            };
+            sinks.push(LogSink {
+                writer,
+                trace: true,
+            });
+        }
+
+        Ok((!sinks.is_empty()).then(|| Self {
+            sinks: Mutex::new(sinks),
+            start: Instant::now(),
+        }))
+    }
+
+    fn write(&self, direction: &str, message: &str) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mut sinks = self.sinks.lock().expect("Failed to lock log file");
+        for sink in sinks.iter_mut() {
+            // Each entry goes out in one write, so a shared sink never splits it.
+            let entry = if sink.trace {
+                format!("{}\n", trace_entry(direction, elapsed, message))
+            } else {
+                format!("{direction} [{elapsed:.6}] {message}\n")
+            };
+            if let Err(e) = sink.writer.write_all(entry.as_bytes()) {
+                errln!("Failed to write to log file: {e}");
+            }
+        }
     }
+}
 
-    stdin
-        .write_all(&did_open_request(file_uri, source))
-        .map_err(|e| format!("Failed to write didOpen request: {e}"))?;
+fn trace_entry(direction: &str, elapsed: f64, message: &str) -> String {
+    let Ok(value) = serde_json::from_str::<Value>(message) else {
+        return format!("{direction} {elapsed:.6} (unparsed)\n{message}");
+    };
 
-    loop {
-        if let Ok(Some(request)) = handle_command(count, commands, file_uri) {
-            stdin
-                .write_all(&request)
-                .map_err(|e| format!("Failed to write reference request: {e}"))?;
+    let method = value
+        .get("method")
+        .and_then(Value::as_str)
+        .unwrap_or("(response)");
+    let id = value
+        .get("id")
+        .map(|id| format!(" id={id}"))
+        .unwrap_or_default();
+    let body = serde_json::to_string_pretty(&value).unwrap_or_else(|_| message.to_string());
+
+    format!("{direction} {elapsed:.6} {method}{id}\n{body}")
+}
+
+fn frame_bodies(request: &[u8]) -> Vec<String> {
+    let mut bodies = Vec::new();
+    let mut rest = request;
+
+    while let Some(header_end) = rest.windows(4).position(|window| window == b"\r\n\r\n") {
+        let header = String::from_utf8_lossy(&rest[..header_end]);
+        let Some(length) = header
+            .trim_start_matches("Content-Length: ")
+            .trim()
+            .parse::<usize>()
+            .ok()
+        else {
+            break;
+        };
+
+        let body_start = header_end + 4;
+        let body_end = (body_start + length).min(rest.len());
+        bodies.push(
+            String::from_utf8_lossy(&rest[body_start..body_end])
+                .trim_end()
+                .to_string(),
+        );
+        rest = &rest[body_end..];
+    }
+
+    bodies
+}
+
+struct ServerWriter {
+    writer: Box<dyn Write + Send>,
+    log: Option<Arc<TrafficLog>>,
+    echo_raw: bool,
+}
+
+impl ServerWriter {
+    fn send(&mut self, request: &[u8], description: &str) -> Result<(), LspError> {
+        if let Some(log) = &self.log {
+            for body in frame_bodies(request) {
+                log.write("-->", &body);
+            }
+        }
+        if self.echo_raw {
+            outln!("--> {}", escape_frame(request));
         }
+
+        self.writer
+            .write_all(request)
+            .map_err(|e| LspError::io(format!("Failed to write {description}"), e))
     }
+}
 
-    stdin
-        .write_all(&did_close_request(file_uri))
-        .map_err(|e| format!("Failed to write didClose request: {e}"))?;
+// Lets a client write through the shared writer, logged and echoed like everything else.
+struct ClientWriter(SharedWriter);
 
-    stdin
-        .write_all(&exit_request())
-        .map_err(|e| format!("Failed to write exit request: {e}"))?;
+impl Write for ClientWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .expect("Failed to lock server")
+            .send(buf, "request")
+            .map_err(io::Error::other)?;
+        Ok(buf.len())
+    }
 
-    Ok(())
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-fn consume_json_rpc_message(reader: &mut BufReader<impl Read>) -> Option<Value> {
-    let red = "\x1b[31m";
-    let normal = "\x1b[0m";
-    let yellow = "\x1b[33m";
+#[derive(Clone, Debug)]
+enum ServerSpec {
+    Spawn { command: String, args: Vec<String> },
+    Socket(String),
+}
 
-    let mut line = String::new();
-    if reader
-        .read_line(&mut line)
-        .expect("Failed to read line from stdout")
-        == 0
-    {
-        return None; // EOF
+impl ServerSpec {
+    fn from_route(command: &str) -> Self {
+        let mut words = command.split_whitespace().map(str::to_string);
+        Self::Spawn {
+            command: words.next().unwrap_or_default(),
+            args: words.collect(),
+        }
     }
 
-    if line.starts_with("Content-Length: ") {
-        let length_str = line.trim_start_matches("Content-Length: ");
-        let length: usize = length_str
-            .trim()
-            .parse()
-            .map_err(|e| format!("Failed to parse Content-Length: {e}"))
-            .expect("Failed to parse Content-Length");
+    fn key(&self) -> String {
+        match self {
+            Self::Spawn { command, args } => std::iter::once(command.as_str())
+                .chain(args.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Self::Socket(address) => address.clone(),
+        }
+    }
+}
+
+type SharedWriter = Arc<Mutex<ServerWriter>>;
+
+struct RunningServer {
+    writer: SharedWriter,
+    reader: JoinHandle<()>,
+    // Set once we have told the server to exit, so its going away isn't reported.
+    closing: Arc<AtomicBool>,
+}
+
+enum Event {
+    Command(Result<Option<String>, LspError>),
+    ServerExited(String),
+}
+
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+struct ServerPool {
+    default: ServerSpec,
+    routes: HashMap<String, ServerSpec>,
+    running: HashMap<String, RunningServer>,
+    children: Vec<(String, Child)>,
+    threads: Vec<JoinHandle<()>>,
+    restart: bool,
+    restarts: Vec<Instant>,
+    events: mpsc::Sender<Event>,
+    session: Arc<Session>,
+    root: PathBuf,
+    initialization_options: Option<Value>,
+    log: Option<Arc<TrafficLog>>,
+    options: DisplayOptions,
+    timeout: Option<Duration>,
+    working_dir: Option<PathBuf>,
+    server_env: Vec<(String, String)>,
+    init_timeout: Duration,
+    echo_stderr: bool,
+}
+
+impl ServerPool {
+    fn spec_for(&self, language_id: &str) -> ServerSpec {
+        self.routes
+            .get(language_id)
+            .unwrap_or(&self.default)
+            .clone()
+    }
 
-        // Read the delimiter (\r\n\r\n)
-        let mut delimiter = [0; 2];
-        reader
-            .read_exact(&mut delimiter)
-            .map_err(|e| format!("Failed to read delimiter: {e}"))
-            .expect("Failed to read delimiter");
+    // Returns the server along with the didOpen it was sent if it had to be restarted.
+    fn server_for(
+        &mut self,
+        language_id: &str,
+    ) -> Result<(SharedWriter, Option<Vec<u8>>), LspError> {
+        let spec = self.spec_for(language_id);
+        let key = spec.key();
+        self.session
+            .routes
+            .lock()
+            .expect("Failed to lock routes")
+            .insert(language_id.to_string(), key.clone());
+        match self.running.get(&key) {
+            Some(server) if !server.reader.is_finished() => {
+                return Ok((server.writer.clone(), None));
+            }
+            Some(_) if !self.restart => {
+                return Err(LspError::Exited(format!(
+                    "Language server {key} has exited"
+                )));
+            }
+            Some(_) => {}
+            None => {
+                let server = self.start(&spec)?;
+                let writer = server.writer.clone();
+                self.running.insert(key, server);
+                return Ok((writer, None));
+            }
+        }
 
-        // Read the JSON message
-        let mut json_buffer = vec![0; length];
-        reader
-            .read_exact(&mut json_buffer)
-            .map_err(|e| format!("Failed to read JSON message: {e}"))
-            .expect("Failed to read JSON message");
+        self.restarts
+            .retain(|restart| restart.elapsed() < RESTART_WINDOW);
+        if self.restarts.len() >= MAX_RESTARTS {
+            return Err(LspError::Exited(format!(
+                "Language server {key} exited {MAX_RESTARTS} times in {}s; not restarting",
+                RESTART_WINDOW.as_secs()
+            )));
+        }
+        self.restarts.push(Instant::now());
 
-        let json_str = String::from_utf8_lossy(&json_buffer);
-        let json_str = json_str.trim_end();
-        if json_str.is_empty() {
-            eprintln!("Received empty JSON message");
-            return None;
+        if let Some(exited) = self.running.remove(&key) {
+            self.threads.push(exited.reader);
         }
+        errln!("Language server {key} exited; restarting");
+        let server = self.start(&spec)?;
+        let writer = server.writer.clone();
+        self.running.insert(key.clone(), server);
 
-        if let Ok(json_value) = serde_json::from_str::<Value>(json_str) {
-            return Some(json_value);
+        let reopen = {
+            let document_guard = self
+                .session
+                .document
+                .lock()
+                .expect("Failed to lock document");
+            (self.spec_for(&document_guard.language_id).key() == key).then(|| {
+                did_open_request(
+                    &document_guard.uri,
+                    &document_guard.source,
+                    &document_guard.language_id,
+                    document_guard.version,
+                )
+            })
+        };
+        if let Some(reopen) = &reopen {
+            writer
+                .lock()
+                .expect("Failed to lock server")
+                .send(reopen, "didOpen request")?;
         }
 
-        println!("{yellow}{json_str}{normal}");
-    } else {
-        eprintln!("Unexpected line: {red}{line}{normal}");
+        Ok((writer, reopen))
+    }
+
+    fn send(
+        &mut self,
+        language_id: &str,
+        request: &[u8],
+        description: &str,
+    ) -> Result<(), LspError> {
+        let (server, reopened) = self.server_for(language_id)?;
+        if reopened.as_deref() == Some(request) {
+            return Ok(());
+        }
+
+        server
+            .lock()
+            .expect("Failed to lock server")
+            .send(request, description)
     }
 
-    None
+    fn start(&mut self, spec: &ServerSpec) -> Result<RunningServer, LspError> {
+        let (reader, writer): ServerStreams = match spec {
+            ServerSpec::Socket(address) => connect_socket(address)?,
+            ServerSpec::Spawn { command, args } => {
+                let mut process = start_server_process(
+                    command,
+                    args,
+                    self.working_dir.as_deref(),
+                    &self.server_env,
+                )?;
+                let stdin = process.stdin.take().expect("Failed to open stdin");
+                let stdout = process.stdout.take().expect("Failed to open stdout");
+                if self.echo_stderr {
+                    let stderr = process.stderr.take().expect("Failed to open stderr");
+                    let colors = self.options.colors;
+                    self.threads.push(thread::spawn(move || {
+                        if let Err(e) = handle_stderr(stderr, colors) {
+                            errln!("{e}");
+                        }
+                    }));
+                }
+                self.children.push((spec.key(), process));
+                (Box::new(stdout), Box::new(stdin))
+            }
+        };
+
+        let server = Arc::new(Mutex::new(ServerWriter {
+            writer,
+            log: self.log.clone(),
+            echo_raw: self.options.echo_raw,
+        }));
+
+        let mut client = LspClient::with_echo(
+            (reader, Box::new(ClientWriter(server.clone()))),
+            self.options.colors,
+            self.options.echo_raw,
+        );
+        client.set_timeout(self.init_timeout);
+        client.set_settings(
+            self.session
+                .settings
+                .lock()
+                .expect("Failed to lock settings")
+                .clone(),
+        );
+
+        let id = self
+            .session
+            .count
+            .lock()
+            .expect("Failed to lock count")
+            .inc();
+        let request = initialize_request(id, &self.root, self.initialization_options.as_ref());
+        self.session
+            .commands
+            .lock()
+            .expect("Failed to lock commands")
+            .insert(id, request.message.clone());
+
+        // Nothing but `initialized` may be sent until the server has answered.
+        let response = match client.call(&request) {
+            Ok(response) => response,
+            Err(LspError::Timeout(_)) => {
+                // A stuck server would otherwise keep shutdown waiting on it forever.
+                self.abandon(spec);
+                return Err(LspError::Timeout(format!(
+                    "Server did not complete initialization within {}s",
+                    self.init_timeout.as_secs()
+                )));
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(error) = response.get("error") {
+            self.abandon(spec);
+            errln!("Language server {} failed to initialize", spec.key());
+            return Err(LspError::server(error));
+        }
+        client.notify(&initialized_notification())?;
+
+        let parts = client.into_parts();
+        for message in parts.notifications.iter().chain([&response]) {
+            if let Some(log) = &self.log {
+                log.write("<--", &message.to_string());
+            }
+            if let Err(e) =
+                display_json_rpc_message(message, &self.session, &spec.key(), &self.options)
+            {
+                errln!("{e}");
+            }
+        }
+
+        let server_clone = server.clone();
+        let session = self.session.clone();
+        let log = self.log.clone();
+        let options = self.options;
+        let timeout = self.timeout;
+        let events = self.events.clone();
+        let closing = Arc::new(AtomicBool::new(false));
+        let reader_closing = closing.clone();
+        let key = spec.key();
+        let reader = thread::spawn(move || {
+            handle_stdout(
+                &parts.messages,
+                (&key, &server_clone),
+                &session,
+                timeout,
+                log.as_deref(),
+                &options,
+            );
+            if !reader_closing.load(Ordering::SeqCst) {
+                let _ = events.send(Event::ServerExited(key));
+            }
+        });
+
+        Ok(RunningServer {
+            writer: server,
+            reader,
+            closing,
+        })
+    }
+
+    // Kills the process `start` just spawned for `spec`, if it spawned one.
+    fn abandon(&mut self, spec: &ServerSpec) {
+        if matches!(spec, ServerSpec::Spawn { .. })
+            && let Some((_, mut child)) = self.children.pop()
+        {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    // Waits for a server process that has closed its output and returns how it exited.
+    fn reap(&mut self, key: &str) -> Option<ExitStatus> {
+        let index = self
+            .children
+            .iter()
+            .rposition(|(child_key, _)| child_key == key)?;
+        let (_, mut child) = self.children.remove(index);
+        child.wait().ok()
+    }
+
+    // Asks the server for the active document's language to shut down, then starts
+    // `command` in its place and opens the document there.
+    fn switch(&mut self, command: &str) -> Result<(), LspError> {
+        let spec = ServerSpec::from_route(command);
+        let language_id = self
+            .session
+            .document
+            .lock()
+            .expect("Failed to lock document")
+            .language_id
+            .clone();
+        let key = self.spec_for(&language_id).key();
+        if let Some(server) = self.running.remove(&key) {
+            self.stop(server);
+        }
+
+        self.routes.insert(language_id.clone(), spec.clone());
+        let server = self.start(&spec)?;
+        self.running.insert(spec.key(), server);
+        errln!("Switched to language server {}", spec.key());
+
+        let request = {
+            let document_guard = self
+                .session
+                .document
+                .lock()
+                .expect("Failed to lock document");
+            did_open_request(
+                &document_guard.uri,
+                &document_guard.source,
+                &document_guard.language_id,
+                document_guard.version,
+            )
+        };
+        self.send(&language_id, &request, "didOpen request")
+    }
+
+    fn stop(&mut self, server: RunningServer) {
+        server.closing.store(true, Ordering::SeqCst);
+        if !server.reader.is_finished() {
+            let id = self
+                .session
+                .count
+                .lock()
+                .expect("Failed to lock count")
+                .inc();
+            let request = shutdown_request(id);
+            self.session
+                .commands
+                .lock()
+                .expect("Failed to lock commands")
+                .insert(id, request.message);
+            self.session
+                .pending
+                .lock()
+                .expect("Failed to lock pending requests")
+                .insert(id, Instant::now());
+
+            let mut writer = server.writer.lock().expect("Failed to lock server");
+            if let Err(e) = writer.send(&request.bytes, "shutdown request") {
+                errln!("{e}");
+            }
+            drop(writer);
+
+            // The server may only be told to exit once it has answered shutdown.
+            let start = Instant::now();
+            while self
+                .session
+                .pending
+                .lock()
+                .expect("Failed to lock pending requests")
+                .contains_key(&id)
+            {
+                if server.reader.is_finished() || start.elapsed() >= self.init_timeout {
+                    break;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+
+            if let Err(e) = server
+                .writer
+                .lock()
+                .expect("Failed to lock server")
+                .send(&exit_request(), "exit request")
+            {
+                errln!("{e}");
+            }
+        }
+        self.threads.push(server.reader);
+    }
+
+    fn all_exited(&self) -> bool {
+        self.running
+            .values()
+            .all(|server| server.reader.is_finished())
+    }
+
+    fn shutdown(mut self) {
+        // Responses still on their way are shown before the servers are asked to go.
+        wait_for_responses(
+            &self.session,
+            self.timeout.unwrap_or(self.init_timeout),
+            || self.all_exited(),
+        );
+
+        for server in self.running.values() {
+            server.closing.store(true, Ordering::SeqCst);
+        }
+        let servers: Vec<RunningServer> = self.running.drain().map(|(_, server)| server).collect();
+        for server in servers {
+            self.stop(server);
+        }
+
+        for thread in self.threads.drain(..) {
+            thread.join().expect("Failed to join server thread");
+        }
+
+        for (_, mut child) in self.children.drain(..) {
+            let status = child.wait().expect("Failed to wait on child process");
+            if !status.success() {
+                errln!("Command exited with status: {status}");
+            }
+        }
+    }
 }
 
-fn handle_stdout(
-    stdout: std::process::ChildStdout,
-    commands: &Arc<Mutex<Vec<Value>>>,
-    echo_commands: bool,
-    echo_responses: bool,
-) {
-    let mut reader = BufReader::new(stdout);
+fn handle_stdin(
+    pool: &mut ServerPool,
+    mut input: Box<dyn CommandSource + Send>,
+    events: &mpsc::Receiver<Event>,
+    session: &Session,
+    config: &Config,
+) -> Result<(), LspError> {
+    let (request, mut active) = {
+        let document_guard = session.document.lock().expect("Failed to lock document");
+        (
+            did_open_request(
+                &document_guard.uri,
+                &document_guard.source,
+                &document_guard.language_id,
+                document_guard.version,
+            ),
+            (
+                document_guard.uri.clone(),
+                document_guard.language_id.clone(),
+            ),
+        )
+    };
+    pool.send(&active.1, &request, "didOpen request")?;
+
+    // Commands are read on their own thread so a server exit can end the session
+    // while the reader is blocked. It only reads the next command once told to, so
+    // commands still run one at a time. If it is blocked when the session ends, it
+    // is left behind and goes away with the process.
+    let commands = pool.events.clone();
+    let (next, wait) = mpsc::channel();
+    thread::spawn(move || {
+        loop {
+            let command = input.read_command();
+            let end = !matches!(command, Ok(Some(_)));
+            if commands.send(Event::Command(command)).is_err() || end || wait.recv().is_err() {
+                break;
+            }
+        }
+    });
 
     loop {
-        let json_value = consume_json_rpc_message(&mut reader);
-        if let Err(e) =
-            display_json_rpc_message(json_value.clone(), commands, echo_commands, echo_responses)
+        let command = match events.recv() {
+            Ok(Event::Command(command)) => match command? {
+                Some(command) => command,
+                None => break,
+            },
+            Ok(Event::ServerExited(_)) if pool.restart => continue,
+            Ok(Event::ServerExited(key)) => {
+                let status = pool
+                    .reap(&key)
+                    .map(|status| format!(" (status: {status})"))
+                    .unwrap_or_default();
+                return Err(LspError::Exited(format!(
+                    "Language server exited unexpectedly{status}"
+                )));
+            }
+            Err(_) => break,
+        };
+
+        if matches!(parse_command(&command), Ok(Command::Quit)) {
+            break;
+        }
+
+        let request = handle_command(session, config, &command);
+        let switch = session
+            .server_switch
+            .lock()
+            .expect("Failed to lock server switch")
+            .take();
+        if let Some(command) = switch
+            && let Err(e) = pool.switch(&command)
         {
-            eprintln!("{e}");
+            errln!("{e}");
+        }
+        let Ok(Some(request)) = request else {
+            let _ = next.send(());
+            continue;
+        };
+
+        let current = {
+            let document_guard = session.document.lock().expect("Failed to lock document");
+            (
+                document_guard.uri.clone(),
+                document_guard.language_id.clone(),
+            )
+        };
+
+        let mut result = Ok(());
+        if current.0 != active.0 {
+            result = pool.send(&active.1, &did_close_request(&active.0), "didClose request");
+        }
+        if result.is_ok() {
+            result = pool.send(&current.1, &request, "request");
+        }
+        active = current;
+
+        if let Err(e) = result {
+            errln!("{e}");
+        }
+
+        // A script's next line may depend on this result, and its output should follow it.
+        if !config.interactive {
+            wait_for_responses(session, pool.timeout.unwrap_or(pool.init_timeout), || {
+                pool.all_exited()
+            });
+        }
+        let _ = next.send(());
+    }
+
+    pool.send(&active.1, &did_close_request(&active.0), "didClose request")
+}
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Waits until no request is waiting on its response or its follow-up, giving up after
+// `timeout` or once `exited` says nobody is left to answer.
+fn wait_for_responses(session: &Session, timeout: Duration, exited: impl Fn() -> bool) {
+    let start = Instant::now();
+    while start.elapsed() < timeout && !exited() {
+        let waiting = !session
+            .pending
+            .lock()
+            .expect("Failed to lock pending requests")
+            .is_empty()
+            || !session
+                .follow_ups
+                .lock()
+                .expect("Failed to lock follow-ups")
+                .is_empty();
+        if !waiting {
             break;
         }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn report_timeouts(session: &Session, timeout: Duration) {
+    let mut pending_guard = session
+        .pending
+        .lock()
+        .expect("Failed to lock pending requests");
+    let mut timed_out: Vec<i32> = pending_guard
+        .iter()
+        .filter(|(_, sent)| sent.elapsed() >= timeout)
+        .map(|(id, _)| *id)
+        .collect();
+    timed_out.sort_unstable();
+
+    for id in timed_out {
+        pending_guard.remove(&id);
+        errln!("{}", LspError::Timeout(format!("Request {id} timed out")));
     }
 }
 
-fn handle_stderr(stderr: std::process::ChildStderr) -> Result<(), String> {
+fn handle_stdout(
+    messages: &Messages,
+    (key, server): (&str, &Arc<Mutex<ServerWriter>>),
+    session: &Session,
+    timeout: Option<Duration>,
+    log: Option<&TrafficLog>,
+    options: &DisplayOptions,
+) {
+    loop {
+        let message = messages.recv_timeout(POLL_INTERVAL);
+
+        if let Some(timeout) = timeout {
+            report_timeouts(session, timeout);
+        }
+
+        let json_value = match message {
+            Ok(Ok(Some(json_value))) => json_value,
+            Ok(Ok(None)) | Err(RecvTimeoutError::Disconnected) => return,
+            Ok(Err(e)) => {
+                errln!("{e}");
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+        };
+
+        if let Some(log) = log {
+            log.write("<--", &json_value.to_string());
+        }
+
+        let response = server_request_response(
+            &json_value,
+            &session.settings.lock().expect("Failed to lock settings"),
+        );
+        if let Some(response) = response
+            && let Err(e) = server
+                .lock()
+                .expect("Failed to lock server")
+                .send(&response, "server request response")
+        {
+            errln!("{e}");
+        }
+
+        // A response that can't be displayed is reported, but later ones still get through.
+        if let Err(e) = display_json_rpc_message(&json_value, session, key, options) {
+            errln!("{e}");
+        }
+
+        if let Some(request) = follow_up_request(session, &json_value)
+            && let Err(e) = server
+                .lock()
+                .expect("Failed to lock server")
+                .send(&request, "follow-up request")
+        {
+            errln!("{e}");
+        }
+    }
+}
+
+fn handle_stderr(stderr: std::process::ChildStderr, colors: Colors) -> Result<(), LspError> {
     let reader = BufReader::new(stderr);
-    let red = "\x1b[31m";
-    let normal = "\x1b[0m";
 
     for line in reader.lines() {
-        let line = line.map_err(|e| format!("Failed to read line from stderr: {e}"))?;
-        eprintln!("{red}stderr: {}{normal}", line.trim_end());
+        let line = line.map_err(|e| LspError::io("Failed to read line from stderr", e))?;
+        errln!("{}stderr: {}{}", colors.red, line.trim_end(), colors.normal);
     }
 
     Ok(())
 }
 
-fn run_server() {
-    let args = Args::parse();
+fn load_initialization_options(path: &Path) -> Result<Value, LspError> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        LspError::io(
+            format!("Failed to read initialization options {}", path.display()),
+            e,
+        )
+    })?;
+    let options = serde_json::from_str::<Value>(&contents).map_err(|e| {
+        LspError::json(
+            format!("Failed to parse initialization options {}", path.display()),
+            e,
+        )
+    })?;
+    if !options.is_object() {
+        return Err(LspError::Usage(format!(
+            "Initialization options in {} must be a JSON object",
+            path.display()
+        )));
+    }
 
-    let mut child = match start_server_process(&args.command) {
-        Ok(child) => child,
-        Err(e) => {
-            eprintln!("{e}");
-            return;
+    Ok(options)
+}
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+const ERROR_SEVERITY: i64 = 1;
+
+struct DiagnosticsWait {
+    session: Arc<Session>,
+    uri: String,
+    timeout: Duration,
+}
+
+impl CommandSource for DiagnosticsWait {
+    fn read_command(&mut self) -> Result<Option<String>, LspError> {
+        let start = Instant::now();
+        while !self
+            .session
+            .diagnostics
+            .lock()
+            .expect("Failed to lock diagnostics")
+            .contains_key(&self.uri)
+        {
+            if start.elapsed() >= self.timeout {
+                errln!("Timed out waiting for diagnostics for {}", self.uri);
+                break;
+            }
+            thread::sleep(POLL_INTERVAL);
         }
-    };
 
-    let count = Arc::new(Mutex::new(Count(0)));
-    let commands = Arc::new(Mutex::new(Vec::new()));
+        Ok(None)
+    }
+}
 
-    let stdin = child.stdin.take().expect("Failed to open stdin");
+fn worst_severity(session: &Session) -> Option<i64> {
+    session
+        .diagnostics
+        .lock()
+        .expect("Failed to lock diagnostics")
+        .values()
+        .flatten()
+        // Diagnostics without a severity are left to the client; treat them as errors.
+        .map(|diagnostic| {
+            diagnostic
+                .get("severity")
+                .and_then(Value::as_i64)
+                .unwrap_or(ERROR_SEVERITY)
+        })
+        .min()
+}
 
-    print!("Enter filename (Default main.c): ");
-    io::stdout().flush().expect("Failed to flush stdout");
+fn run_server() -> ExitCode {
+    let mut args = Args::parse();
+    match config::load() {
+        Ok(file) => args.merge(file),
+        Err(e) => {
+            errln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    }
 
-    let mut buffer = String::new();
-    io::stdin().read_line(&mut buffer).expect("Failed to read line");
+    let colors =
+        Colors::new(!args.no_color && args.format == Format::Text && io::stdout().is_terminal());
 
-    let mut filename = buffer
-        .trim()
-        .to_string();
+    // Stdin holds the document itself, so there is nowhere left to read commands from.
+    if args.stdin && args.script.is_none() && !args.check {
+        errln!("Error: --stdin needs commands from --script or --check");
+        return ExitCode::FAILURE;
+    }
 
-    if filename.is_empty() {
-        filename = "main.c".to_string();
+    if let Some(working_dir) = &args.working_dir
+        && !working_dir.is_dir()
+    {
+        errln!(
+            "Error: Working directory {} does not exist",
+            working_dir.display()
+        );
+        return ExitCode::FAILURE;
     }
 
-    let (file_uri, source) = process_file(&PathBuf::from(filename)).expect("Error processing file");
+    let file_path = match (&args.file, &args.uri) {
+        (_, Some(path)) if args.stdin => path.clone(),
+        (Some(path), _) => path.clone(),
+        // Piped stdin carries commands, so only a terminal gets asked for the file.
+        _ if io::stdin().is_terminal() && !args.check => {
+            if !args.quiet {
+                output::print("Enter filename (Default main.c): ");
+            }
+
+            let mut buffer = String::new();
+            io::stdin()
+                .read_line(&mut buffer)
+                .expect("Failed to read line");
 
-    let commands_clone = commands.clone();
-    let stdin_handle = thread::spawn(move || {
-        if let Err(e) = handle_stdin(stdin, &count, &file_uri, &source, &commands_clone) {
-            eprintln!("{e}");
+            let mut filename = buffer.trim().to_string();
+
+            if filename.is_empty() {
+                filename = "main.c".to_string();
+            }
+
+            PathBuf::from(filename)
         }
-    });
+        _ => {
+            errln!("Error: No file given; pass the file to open as an argument");
+            return ExitCode::FAILURE;
+        }
+    };
+    let document = if args.stdin {
+        process_stdin(&file_path)
+    } else {
+        process_file(&file_path)
+    };
+    let (file_uri, source) = match document {
+        Ok(document) => document,
+        Err(e) => {
+            errln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let language_id = args
+        .language
+        .clone()
+        .unwrap_or_else(|| language_id(&file_path).to_string());
+    let root = match &args.root {
+        Some(root) => fs::canonicalize(root),
+        None if args.stdin => std::path::absolute(&file_path)
+            .map(|path| path.parent().map(Path::to_path_buf).unwrap_or(path)),
+        None => fs::canonicalize(&file_path)
+            .map(|path| path.parent().map(Path::to_path_buf).unwrap_or(path)),
+    };
+    let root = match root {
+        Ok(root) => root,
+        Err(e) => {
+            errln!("Error: Unable to resolve workspace root: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // Log files are only created once the document is known to be readable, so a
+    // mistyped command line can't truncate anything.
+    let log = match TrafficLog::create(args.log_file.as_ref(), args.trace.as_ref()) {
+        Ok(log) => log.map(Arc::new),
+        Err(e) => {
+            errln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-    let stdout = child.stdout.take().expect("Failed to open stdout");
-    let commands_clone = commands;
-    let stdout_handle = thread::spawn(move || {
-        handle_stdout(
-            stdout,
-            &commands_clone,
-            args.echo_commands || args.debug,
-            args.echo_responses || args.debug,
-        );
+    let initialization_options = match args
+        .initialization_options
+        .as_deref()
+        .map(load_initialization_options)
+        .transpose()
+    {
+        Ok(options) => options,
+        Err(e) => {
+            errln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let config = Config {
+        language: args.language.clone(),
+        quiet: args.quiet,
+        interactive: args.script.is_none() && !args.check && io::stdin().is_terminal(),
+        offsets: args.offsets,
+    };
+
+    let mut session = Session::new(Document {
+        uri: file_uri,
+        source,
+        language_id,
+        version: 1,
+        from_stdin: args.stdin,
     });
+    session.sync_kind = args.sync_kind;
+    session.format = args.format;
+    if let Some(path) = &args.output_file {
+        match fs::File::create(path) {
+            Ok(file) => *session.output.lock().expect("Failed to lock output file") = Some(file),
+            Err(e) => {
+                errln!("Failed to create output file {}: {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let session = Arc::new(session);
 
-    let stderr_handle = if args.echo_stderr || args.debug {
-        let stderr = child.stderr.take().expect("Failed to open stderr");
-        Some(thread::spawn(move || {
-            if let Err(e) = handle_stderr(stderr) {
-                eprintln!("{e}");
+    let input: Box<dyn CommandSource + Send> = match &args.script {
+        _ if args.check => Box::new(DiagnosticsWait {
+            session: session.clone(),
+            uri: session
+                .document
+                .lock()
+                .expect("Failed to lock document")
+                .uri
+                .clone(),
+            timeout: args.timeout.map_or(CHECK_TIMEOUT, Duration::from_millis),
+        }),
+        Some(script) => match fs::File::open(script) {
+            Ok(file) => Box::new(LineSource(BufReader::new(file))),
+            Err(e) => {
+                errln!("Failed to open script {}: {e}", script.display());
+                return ExitCode::FAILURE;
             }
-        }))
-    } else {
-        None
+        },
+        None if io::stdin().is_terminal() => match EditorSource::new() {
+            Ok(editor) => Box::new(editor),
+            Err(e) => {
+                errln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(LineSource(BufReader::new(io::stdin()))),
     };
 
-    stdin_handle.join().expect("Failed to join stdin thread");
-    stdout_handle.join().expect("Failed to join stdout thread");
+    let default = match &args.socket {
+        Some(address) => ServerSpec::Socket(address.clone()),
+        None => ServerSpec::Spawn {
+            command: args
+                .command
+                .clone()
+                .unwrap_or_else(|| DEFAULT_COMMAND.to_string()),
+            args: args.server_args.clone(),
+        },
+    };
+    let (event_sender, events) = mpsc::channel();
+    let mut pool = ServerPool {
+        default,
+        routes: args
+            .servers
+            .iter()
+            .map(|(language, command)| (language.clone(), ServerSpec::from_route(command)))
+            .collect(),
+        running: HashMap::new(),
+        children: Vec::new(),
+        threads: Vec::new(),
+        restart: args.restart,
+        restarts: Vec::new(),
+        events: event_sender,
+        session: session.clone(),
+        root,
+        initialization_options,
+        log,
+        options: DisplayOptions {
+            echo_commands: args.echo_commands || args.debug,
+            echo_responses: args.echo_responses || args.debug,
+            echo_raw: args.echo_raw,
+            format: args.format,
+            colors,
+            one_based: args.one_based,
+            quiet: args.quiet,
+            context: args.context,
+            max_results: args.max_results,
+            timings: args.timings,
+            raw_hover: args.raw_hover,
+            diagnostics_only: args.check,
+        },
+        timeout: args.timeout.map(Duration::from_millis),
+        working_dir: args.working_dir.clone(),
+        server_env: args.server_env.clone(),
+        init_timeout: Duration::from_secs(args.init_timeout),
+        echo_stderr: args.echo_stderr || args.debug,
+    };
 
-    if let Some(stderr_handle) = stderr_handle {
-        stderr_handle.join().expect("Failed to join stderr thread");
+    let result = handle_stdin(&mut pool, input, &events, &session, &config);
+    if let Err(e) = &result {
+        errln!("{e}");
     }
 
-    let status = child.wait().expect("Failed to wait on child process");
-    if !status.success() {
-        eprintln!("Command exited with status: {status}");
+    pool.shutdown();
+
+    if result.is_err() || (args.check && worst_severity(&session) == Some(ERROR_SEVERITY)) {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
     }
 }
 
-fn main() {
-    run_server();
+fn main() -> ExitCode {
+    run_server()
 }
@@ -0,0 +1,77 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Mutex, PoisonError};
+
+// Draws text above a line editor's prompt and redraws the prompt after it.
+pub type Printer = Box<dyn FnMut(String) + Send>;
+
+struct Terminal {
+    printer: Option<Printer>,
+    stdout: bool,
+    stderr: bool,
+}
+
+// The command thread and every server thread print. Stdout and stderr share one lock so
+// a prompt or an error can't land in the middle of another thread's output.
+static LOCK: Mutex<Terminal> = Mutex::new(Terminal {
+    printer: None,
+    stdout: false,
+    stderr: false,
+});
+
+// Hands output for whichever of stdout and stderr is the terminal to the line editor, which
+// draws its prompt outside this lock.
+pub fn set_printer(printer: Printer) {
+    let mut terminal = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    terminal.stdout = io::stdout().is_terminal();
+    terminal.stderr = io::stderr().is_terminal();
+    terminal.printer = Some(printer);
+}
+
+fn write_locked(mut writer: impl Write, to_terminal: fn(&Terminal) -> bool, text: &str) {
+    let mut terminal = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    if to_terminal(&terminal)
+        && let Some(printer) = terminal.printer.as_mut()
+    {
+        printer(text.to_string());
+        return;
+    }
+    // Nothing sensible can be done when the terminal itself won't take output.
+    let _ = writer.write_all(text.as_bytes());
+    let _ = writer.flush();
+}
+
+pub fn print(text: &str) {
+    write_locked(io::stdout().lock(), |terminal| terminal.stdout, text);
+}
+
+pub fn eprint(text: &str) {
+    write_locked(io::stderr().lock(), |terminal| terminal.stderr, text);
+}
+
+#[macro_export]
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        $crate::output::print(&format!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[macro_export]
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        $crate::output::eprint(&format!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+// Stderr behind the same lock, for writers that want an `impl Write`.
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        eprint(&String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -1,10 +1,59 @@
-use crate::Count;
+use crate::command::{Command, command_names, find_command, parse_command};
+use crate::display::{Format, noun, write_output};
+use crate::edit::{PositionEncoding, apply_edits, offset_to_position};
+use crate::error::LspError;
+use crate::{
+    Config, Document, FollowUp, Session, SyncKind, path_to_uri, process_file, uri_to_path,
+};
+use crate::{errln, outln, output};
 use serde_json::{Value, json};
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::fs;
 use std::io;
+use std::path::Path;
+use std::time::Instant;
 
 const RPC_VERSION: &str = "2.0";
-const LANGUAGE_ID: &str = "c";
+const DEFAULT_LANGUAGE_ID: &str = "plaintext";
+// Where bare `def` and `ref` look when there is no one to ask.
+const DEFAULT_POSITION: (usize, usize) = (9, 4);
+
+pub fn language_id(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "c" => "c",
+        "cpp" | "cc" | "cxx" | "c++" | "h" | "hh" | "hpp" | "hxx" => "cpp",
+        "m" => "objective-c",
+        "mm" => "objective-cpp",
+        "rs" => "rust",
+        "py" => "python",
+        "go" => "go",
+        "java" => "java",
+        "js" => "javascript",
+        "ts" => "typescript",
+        "rb" => "ruby",
+        "lua" => "lua",
+        "sh" | "bash" => "shellscript",
+        "json" => "json",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "md" => "markdown",
+        "zig" => "zig",
+        _ => DEFAULT_LANGUAGE_ID,
+    }
+}
+
+// A request together with its encoded frame, so the id that gets tracked is the one that was sent.
+pub struct Request {
+    pub id: i32,
+    pub message: Value,
+    pub bytes: Vec<u8>,
+}
 
 fn create_request(method: &str, params: &Value, id: Option<i32>) -> Value {
     let mut request = json!({
@@ -20,27 +69,122 @@ fn create_request(method: &str, params: &Value, id: Option<i32>) -> Value {
     request
 }
 
+fn create_response(id: &Value, result: &Value) -> Value {
+    json!({
+        "jsonrpc": RPC_VERSION,
+        "id": id,
+        "result": result,
+    })
+}
+
+fn create_error_response(id: &Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": RPC_VERSION,
+        "id": id,
+        "error": {
+            "code": code,
+            "message": message,
+        },
+    })
+}
+
+// Content-Length counts the bytes of the JSON body, and nothing follows the body.
 fn generate_rpc_request(request: &Value) -> Vec<u8> {
-    let request_json = request.to_string() + "\r\n";
-    let content_length = request_json.len();
-    format!("Content-Length: {content_length}\r\n\r\n{request_json}")
-        .as_bytes()
-        .to_vec()
+    let body = request.to_string();
+    let mut frame = format!("Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+    frame.extend_from_slice(body.as_bytes());
+    frame
+}
+
+fn build_request(method: &str, params: &Value, id: i32) -> Request {
+    let message = create_request(method, params, Some(id));
+    let bytes = generate_rpc_request(&message);
+    Request { id, message, bytes }
+}
+
+// Sends whatever the caller wrote, with only the id and version filled in.
+pub fn raw_request(n: i32, mut message: serde_json::Map<String, Value>) -> Request {
+    message.insert("jsonrpc".to_string(), json!(RPC_VERSION));
+    message.insert("id".to_string(), json!(n));
+    let message = Value::Object(message);
+    let bytes = generate_rpc_request(&message);
+    Request {
+        id: n,
+        message,
+        bytes,
+    }
+}
+
+// Older servers read rootPath and newer ones rootUri or workspaceFolders, so all three
+// describe the same directory.
+pub fn initialize_request(n: i32, root: &Path, initialization_options: Option<&Value>) -> Request {
+    let root_uri = path_to_uri(root);
+    let name = root
+        .file_name()
+        .map_or_else(|| root.to_string_lossy(), |name| name.to_string_lossy());
+    let mut params = json!({
+        "clientInfo": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "rootPath": root.to_string_lossy(),
+        "rootUri": root_uri,
+        "capabilities": {
+            "general": {
+                "positionEncodings": ["utf-8", "utf-16"]
+            },
+            "textDocument": {
+                "documentSymbol": {
+                    "hierarchicalDocumentSymbolSupport": true
+                },
+                "hover": {
+                    "contentFormat": ["markdown", "plaintext"]
+                },
+                "rename": {
+                    "prepareSupport": true
+                }
+            },
+            "window": {
+                "workDoneProgress": true
+            },
+            "workspace": {
+                "configuration": true,
+                "didChangeConfiguration": {
+                    "dynamicRegistration": false
+                }
+            }
+        },
+        "workspaceFolders": [
+            {
+                "uri": root_uri,
+                "name": name
+            }
+        ]
+    });
+    if let Some(initialization_options) = initialization_options {
+        params["initializationOptions"] = initialization_options.clone();
+    }
+    build_request("initialize", &params, n)
 }
 
-pub fn initialize_request(n: i32) -> Vec<u8> {
-    let request = create_request("initialize", &json!({}), Some(n));
+pub fn initialized_notification() -> Vec<u8> {
+    let request = create_request("initialized", &json!({}), None);
     generate_rpc_request(&request)
 }
 
-pub fn did_open_request(file_uri_str: &str, source: &str) -> Vec<u8> {
+pub fn did_open_request(
+    file_uri_str: &str,
+    source: &str,
+    language_id: &str,
+    version: i32,
+) -> Vec<u8> {
     let request = create_request(
         "textDocument/didOpen",
         &json!({
             "textDocument": {
                 "uri": file_uri_str,
-                "languageId": LANGUAGE_ID,
-                "version": 1,
+                "languageId": language_id,
+                "version": version,
                 "text": source
             }
         }),
@@ -49,8 +193,8 @@ pub fn did_open_request(file_uri_str: &str, source: &str) -> Vec<u8> {
     generate_rpc_request(&request)
 }
 
-fn definition_request(n: i32, file_uri_str: &str, line: usize, character: usize) -> Vec<u8> {
-    let request = create_request(
+pub fn definition_request(n: i32, file_uri_str: &str, line: usize, character: usize) -> Request {
+    build_request(
         "textDocument/definition",
         &json!({
             "textDocument": {
@@ -61,13 +205,34 @@ fn definition_request(n: i32, file_uri_str: &str, line: usize, character: usize)
                 "character": character
             }
         }),
-        Some(n),
-    );
-    generate_rpc_request(&request)
+        n,
+    )
 }
 
-fn reference_request(n: i32, file_uri_str: &str, line: usize, character: usize) -> Vec<u8> {
-    let request = create_request(
+pub fn hover_request(n: i32, file_uri_str: &str, line: usize, character: usize) -> Request {
+    build_request(
+        "textDocument/hover",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "position": {
+                "line": line,
+                "character": character
+            }
+        }),
+        n,
+    )
+}
+
+pub fn reference_request(
+    n: i32,
+    file_uri_str: &str,
+    line: usize,
+    character: usize,
+    include_declaration: bool,
+) -> Request {
+    build_request(
         "textDocument/references",
         &json!({
             "textDocument": {
@@ -76,26 +241,374 @@ fn reference_request(n: i32, file_uri_str: &str, line: usize, character: usize)
             "position": {
                 "line": line,
                 "character": character
+            },
+            "context": {
+                "includeDeclaration": include_declaration
             }
         }),
-        Some(n),
-    );
-    generate_rpc_request(&request)
+        n,
+    )
 }
 
-fn document_symbol_request(n: i32, file_uri_str: &str) -> Vec<u8> {
-    let request = create_request(
+pub fn completion_request(n: i32, file_uri_str: &str, line: usize, character: usize) -> Request {
+    build_request(
+        "textDocument/completion",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "position": {
+                "line": line,
+                "character": character
+            }
+        }),
+        n,
+    )
+}
+
+pub fn completion_resolve_request(n: i32, item: &Value) -> Request {
+    build_request("completionItem/resolve", item, n)
+}
+
+pub fn declaration_request(n: i32, file_uri_str: &str, line: usize, character: usize) -> Request {
+    build_request(
+        "textDocument/declaration",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "position": {
+                "line": line,
+                "character": character
+            }
+        }),
+        n,
+    )
+}
+
+pub fn document_symbol_request(n: i32, file_uri_str: &str) -> Request {
+    build_request(
         "textDocument/documentSymbol",
         &json!({
             "textDocument": {
                 "uri": file_uri_str
             }
         }),
-        Some(n),
+        n,
+    )
+}
+
+pub fn did_change_request(file_uri_str: &str, version: i32, source: &str) -> Vec<u8> {
+    let request = create_request(
+        "textDocument/didChange",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str,
+                "version": version
+            },
+            "contentChanges": [
+                {
+                    "text": source
+                }
+            ]
+        }),
+        None,
     );
     generate_rpc_request(&request)
 }
 
+pub fn did_change_range_request(
+    file_uri_str: &str,
+    version: i32,
+    range: &Value,
+    text: &str,
+) -> Vec<u8> {
+    let request = create_request(
+        "textDocument/didChange",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str,
+                "version": version
+            },
+            "contentChanges": [
+                {
+                    "range": range,
+                    "text": text
+                }
+            ]
+        }),
+        None,
+    );
+    generate_rpc_request(&request)
+}
+
+// The save option is either a bare bool or SaveOptions saying whether to send the text.
+fn server_wants_saved_text(capabilities: Option<&Value>) -> bool {
+    capabilities
+        .and_then(|capabilities| capabilities.get("textDocumentSync"))
+        .and_then(|sync| sync.get("save"))
+        .and_then(|save| save.get("includeText"))
+        .and_then(Value::as_bool)
+        .unwrap_or_default()
+}
+
+// FileChangeType from the spec; only Changed is sent so far.
+pub const FILE_CHANGED: u32 = 2;
+
+pub fn did_change_watched_files_request(file_uri_str: &str, change_type: u32) -> Vec<u8> {
+    let request = create_request(
+        "workspace/didChangeWatchedFiles",
+        &json!({
+            "changes": [
+                {
+                    "uri": file_uri_str,
+                    "type": change_type
+                }
+            ]
+        }),
+        None,
+    );
+    generate_rpc_request(&request)
+}
+
+// A didChange for the document, followed by a watched-files notice for servers
+// that track the file on disk rather than the open buffer.
+fn document_changed(document: &Document, sync_kind: SyncKind) -> Vec<u8> {
+    let mut request = match sync_kind {
+        SyncKind::None => Vec::new(),
+        SyncKind::Full | SyncKind::Incremental => {
+            did_change_request(&document.uri, document.version, &document.source)
+        }
+    };
+    request.extend(did_change_watched_files_request(
+        &document.uri,
+        FILE_CHANGED,
+    ));
+    request
+}
+
+pub fn did_change_configuration_request(settings: &Value) -> Vec<u8> {
+    let request = create_request(
+        "workspace/didChangeConfiguration",
+        &json!({
+            "settings": settings
+        }),
+        None,
+    );
+    generate_rpc_request(&request)
+}
+
+pub fn code_action_request(
+    n: i32,
+    file_uri_str: &str,
+    range: &Value,
+    diagnostics: &[Value],
+) -> Request {
+    build_request(
+        "textDocument/codeAction",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "range": range,
+            "context": {
+                "diagnostics": diagnostics
+            }
+        }),
+        n,
+    )
+}
+
+pub fn formatting_request(n: i32, file_uri_str: &str) -> Request {
+    build_request(
+        "textDocument/formatting",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "options": {
+                "tabSize": 4,
+                "insertSpaces": true
+            }
+        }),
+        n,
+    )
+}
+
+pub fn semantic_tokens_request(n: i32, file_uri_str: &str) -> Request {
+    build_request(
+        "textDocument/semanticTokens/full",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            }
+        }),
+        n,
+    )
+}
+
+pub fn inlay_hint_request(n: i32, file_uri_str: &str, range: &Value) -> Request {
+    build_request(
+        "textDocument/inlayHint",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "range": range
+        }),
+        n,
+    )
+}
+
+pub fn folding_range_request(n: i32, file_uri_str: &str) -> Request {
+    build_request(
+        "textDocument/foldingRange",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            }
+        }),
+        n,
+    )
+}
+
+pub fn document_link_request(n: i32, file_uri_str: &str) -> Request {
+    build_request(
+        "textDocument/documentLink",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            }
+        }),
+        n,
+    )
+}
+
+pub fn document_color_request(n: i32, file_uri_str: &str) -> Request {
+    build_request(
+        "textDocument/documentColor",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            }
+        }),
+        n,
+    )
+}
+
+pub fn color_presentation_request(
+    n: i32,
+    file_uri_str: &str,
+    color: &Value,
+    range: &Value,
+) -> Request {
+    build_request(
+        "textDocument/colorPresentation",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "color": color,
+            "range": range
+        }),
+        n,
+    )
+}
+
+pub fn selection_range_request(
+    n: i32,
+    file_uri_str: &str,
+    positions: &[(usize, usize)],
+) -> Request {
+    let positions: Vec<Value> = positions
+        .iter()
+        .map(|(line, character)| json!({ "line": line, "character": character }))
+        .collect();
+    build_request(
+        "textDocument/selectionRange",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "positions": positions
+        }),
+        n,
+    )
+}
+
+pub fn call_hierarchy_prepare_request(
+    n: i32,
+    file_uri_str: &str,
+    line: usize,
+    character: usize,
+) -> Request {
+    build_request(
+        "textDocument/prepareCallHierarchy",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "position": {
+                "line": line,
+                "character": character
+            }
+        }),
+        n,
+    )
+}
+
+pub fn call_hierarchy_incoming_request(n: i32, item: &Value) -> Request {
+    build_request("callHierarchy/incomingCalls", &json!({ "item": item }), n)
+}
+
+pub fn call_hierarchy_outgoing_request(n: i32, item: &Value) -> Request {
+    build_request("callHierarchy/outgoingCalls", &json!({ "item": item }), n)
+}
+
+pub fn prepare_rename_request(
+    n: i32,
+    file_uri_str: &str,
+    line: usize,
+    character: usize,
+) -> Request {
+    build_request(
+        "textDocument/prepareRename",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "position": {
+                "line": line,
+                "character": character
+            }
+        }),
+        n,
+    )
+}
+
+pub fn rename_request(
+    n: i32,
+    file_uri_str: &str,
+    line: usize,
+    character: usize,
+    new_name: &str,
+) -> Request {
+    build_request(
+        "textDocument/rename",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "position": {
+                "line": line,
+                "character": character
+            },
+            "newName": new_name
+        }),
+        n,
+    )
+}
+
 pub fn did_close_request(file_uri_str: &str) -> Vec<u8> {
     let request = create_request(
         "textDocument/didClose",
@@ -109,86 +622,965 @@ pub fn did_close_request(file_uri_str: &str) -> Vec<u8> {
     generate_rpc_request(&request)
 }
 
+// TextDocumentSaveReason from the spec; `save` is always a manual save.
+pub const SAVE_MANUAL: u32 = 1;
+
+pub fn will_save_request(file_uri_str: &str, reason: u32) -> Vec<u8> {
+    let request = create_request(
+        "textDocument/willSave",
+        &json!({
+            "textDocument": {
+                "uri": file_uri_str
+            },
+            "reason": reason
+        }),
+        None,
+    );
+    generate_rpc_request(&request)
+}
+
+pub fn did_save_request(file_uri_str: &str, include_text: bool, text: &str) -> Vec<u8> {
+    let mut params = json!({
+        "textDocument": {
+            "uri": file_uri_str
+        }
+    });
+    if include_text {
+        params["text"] = json!(text);
+    }
+    let request = create_request("textDocument/didSave", &params, None);
+    generate_rpc_request(&request)
+}
+
+pub fn cancel_notification(id: i32) -> Vec<u8> {
+    let request = create_request("$/cancelRequest", &json!({ "id": id }), None);
+    generate_rpc_request(&request)
+}
+
+pub fn shutdown_request(n: i32) -> Request {
+    build_request("shutdown", &Value::Null, n)
+}
+
 pub fn exit_request() -> Vec<u8> {
     let request = create_request("exit", &Value::Null, None);
     generate_rpc_request(&request)
 }
 
+// A dotted section such as "python.analysis" names a nested object in the settings.
+fn configuration_section(settings: &Value, item: &Value) -> Value {
+    let section = match item.get("section").and_then(Value::as_str) {
+        Some(section) => section
+            .split('.')
+            .try_fold(settings, |value, key| value.get(key)),
+        None => Some(settings),
+    };
+
+    section
+        .filter(|section| !section.is_null())
+        .cloned()
+        .unwrap_or_else(|| json!({}))
+}
+
+pub fn server_request_response(message: &Value, settings: &Value) -> Option<Vec<u8>> {
+    let id = message.get("id")?;
+    let method = message.get("method").and_then(Value::as_str)?;
+
+    let response = match method {
+        "workspace/configuration" => {
+            let items = message
+                .get("params")
+                .and_then(|params| params.get("items"))
+                .and_then(Value::as_array)
+                .map_or_else(
+                    || vec![configuration_section(settings, &Value::Null)],
+                    |items| {
+                        items
+                            .iter()
+                            .map(|item| configuration_section(settings, item))
+                            .collect()
+                    },
+                );
+            create_response(id, &Value::Array(items))
+        }
+        "window/workDoneProgress/create"
+        | "window/showMessageRequest"
+        | "client/registerCapability"
+        | "client/unregisterCapability" => create_response(id, &Value::Null),
+        _ => create_error_response(id, -32601, &format!("Unhandled method: {method}")),
+    };
+
+    Some(generate_rpc_request(&response))
+}
+
+fn apply_formatting(
+    session: &Session,
+    uri: &str,
+    response: &Value,
+) -> Result<Option<Vec<u8>>, LspError> {
+    let edits = match response.get("result").and_then(Value::as_array) {
+        Some(edits) if !edits.is_empty() => edits,
+        _ => return Ok(None),
+    };
+
+    let mut document_guard = session.document.lock().expect("Failed to lock document");
+    // The edits are for the text the request was made against.
+    if document_guard.uri != uri {
+        errln!("Not applying formatting edits: {uri} is no longer the active document");
+        return Ok(None);
+    }
+    let encoding = session.server_for(&document_guard.language_id).encoding;
+    let formatted = apply_edits(&document_guard.source, edits, encoding)?;
+
+    // A document from stdin has no file of its own; --uri may name someone else's.
+    if document_guard.from_stdin {
+        write_output(session, &formatted);
+    } else {
+        let path = uri_to_path(&document_guard.uri).ok_or_else(|| {
+            LspError::Usage(format!("Cannot write non-file URI {}", document_guard.uri))
+        })?;
+        fs::write(&path, &formatted)
+            .map_err(|e| LspError::io(format!("Failed to write {}", path.display()), e))?;
+        status(
+            session,
+            &format!(
+                "Applied {} {} to {}",
+                edits.len(),
+                noun(edits.len(), ("edit", "edits")),
+                path.display()
+            ),
+        );
+    }
+
+    document_guard.source = formatted;
+    document_guard.version += 1;
+
+    Ok(Some(document_changed(&document_guard, session.sync_kind)))
+}
+
+pub fn follow_up_request(session: &Session, response: &Value) -> Option<Vec<u8>> {
+    let id = response
+        .get("id")
+        .and_then(Value::as_i64)
+        .and_then(|id| i32::try_from(id).ok())?;
+    let follow_up = session
+        .follow_ups
+        .lock()
+        .expect("Failed to lock follow-ups")
+        .remove(&id)?;
+
+    if response.get("error").is_some() {
+        return None;
+    }
+
+    let request = match follow_up {
+        FollowUp::Request(method) => {
+            let item = response.get("result")?.as_array()?.first()?;
+            let n = session.count.lock().expect("Failed to lock count").inc();
+            build_request(method, &json!({ "item": item }), n)
+        }
+        FollowUp::ApplyEdits { uri } => {
+            return apply_formatting(session, &uri, response).unwrap_or_else(|e| {
+                errln!("{e}");
+                None
+            });
+        }
+        FollowUp::Rename {
+            uri,
+            line,
+            character,
+            new_name,
+        } => {
+            if response.get("result").is_none_or(Value::is_null) {
+                errln!("Refusing to rename: the server says this position isn't renamable");
+                return None;
+            }
+            let n = session.count.lock().expect("Failed to lock count").inc();
+            rename_request(n, &uri, line, character, &new_name)
+        }
+    };
+
+    Some(track_request(
+        request,
+        &mut session.commands.lock().expect("Failed to lock commands"),
+        &mut session
+            .pending
+            .lock()
+            .expect("Failed to lock pending requests"),
+    ))
+}
+
+fn position_key(position: Option<&Value>) -> Option<(u64, u64)> {
+    let position = position?;
+    Some((
+        position.get("line")?.as_u64()?,
+        position.get("character")?.as_u64()?,
+    ))
+}
+
+fn diagnostic_contains(diagnostic: &Value, line: usize, character: usize) -> bool {
+    let Some(range) = diagnostic.get("range") else {
+        return false;
+    };
+    let position = (line as u64, character as u64);
+
+    matches!(
+        (position_key(range.get("start")), position_key(range.get("end"))),
+        (Some(start), Some(end)) if start <= position && position <= end
+    )
+}
+
+fn prompt_position() -> Option<(usize, usize)> {
+    let read = |prompt: &str| {
+        output::print(prompt);
+        let mut buffer = String::new();
+        io::stdin().read_line(&mut buffer).ok()?;
+        buffer.trim().parse().ok()
+    };
+
+    let line = read("line: ")?;
+    let character = read("character: ")?;
+    Some((line, character))
+}
+
+fn open_document(file_path: &Path, config: &Config) -> Result<Document, LspError> {
+    let (uri, source) = process_file(file_path)?;
+    let language_id = config
+        .language
+        .clone()
+        .unwrap_or_else(|| language_id(file_path).to_string());
+
+    Ok(Document {
+        uri,
+        source,
+        language_id,
+        version: 1,
+        from_stdin: false,
+    })
+}
+
+fn track_request(
+    request: Request,
+    commands: &mut HashMap<i32, Value>,
+    pending: &mut HashMap<i32, Instant>,
+) -> Vec<u8> {
+    pending.insert(request.id, Instant::now());
+    commands.insert(request.id, request.message);
+    request.bytes
+}
+
+// Status lines are for people; JSON output keeps stdout to one object per line.
+fn status(session: &Session, message: &str) {
+    if session.format == Format::Text {
+        outln!("{message}");
+    } else {
+        errln!("{message}");
+    }
+}
+
+fn display_capabilities(capabilities: Option<&Value>, format: Format) -> String {
+    if format != Format::Text {
+        return format!("{}\n", json!({ "method": "caps", "result": capabilities }));
+    }
+
+    let Some(capabilities) = capabilities else {
+        return "Server capabilities are not available yet.\n".to_string();
+    };
+
+    let mut out = String::new();
+
+    for capability in [
+        "definitionProvider",
+        "referencesProvider",
+        "hoverProvider",
+        "documentSymbolProvider",
+        "positionEncoding",
+    ] {
+        let supported = capabilities
+            .get(capability)
+            .filter(|value| !value.is_null() && **value != Value::Bool(false))
+            .map_or_else(|| "no".to_string(), Value::to_string);
+        out.push_str(&format!("{capability}: {supported}\n"));
+    }
+    out
+}
+
+// Rewrites each @<offset> in the position slots a command's usage defines (a <line> <col>
+// pair each) as its line and column, leaving the rest of the command, such as the text of
+// an edit, untouched.
+fn resolve_offsets(
+    command: &str,
+    source: &str,
+    encoding: PositionEncoding,
+) -> Result<String, LspError> {
+    let command = command.trim();
+    let (name, mut rest) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let slots = find_command(name).map_or(0, |spec| spec.usage.matches("col>").count() * 2);
+
+    let mut resolved = name.to_string();
+    let mut filled = 0;
+    loop {
+        let trimmed = rest.trim_start();
+        let (word, after) = trimmed
+            .split_once(char::is_whitespace)
+            .unwrap_or((trimmed, ""));
+        if filled % 2 == 0
+            && filled < slots
+            && let Some(offset) = word.strip_prefix('@')
+        {
+            let offset = offset
+                .parse()
+                .map_err(|_| LspError::Usage(format!("Invalid byte offset: {word}")))?;
+            let (line, character) = offset_to_position(source, offset, encoding)?;
+            resolved.push_str(&format!(" {line} {character}"));
+            filled += 2;
+        } else if filled < slots && !word.is_empty() && word.parse::<usize>().is_ok() {
+            resolved.push(' ');
+            resolved.push_str(word);
+            filled += 1;
+        } else {
+            if !trimmed.is_empty() {
+                resolved.push(' ');
+                resolved.push_str(trimmed);
+            }
+            return Ok(resolved);
+        }
+        rest = after;
+    }
+}
+
 pub fn handle_command(
-    count: &Arc<Mutex<Count>>,
-    commands: &std::sync::Arc<std::sync::Mutex<Vec<Value>>>,
-    file_uri: &str,
-) -> Result<Option<Vec<u8>>, String> {
-    let mut buffer = String::new();
-    io::stdin().read_line(&mut buffer).expect("Failed to read line");
-    let command = buffer.to_string();
-
-    if command.is_empty() {
+    session: &Session,
+    config: &Config,
+    command: &str,
+) -> Result<Option<Vec<u8>>, LspError> {
+    if command.trim().is_empty() {
         return Ok(None);
     }
 
-    let mut count_guard = count.lock().expect("Failed to lock count");
-    let mut commands_guard = commands.lock().expect("Failed to lock commands");
+    let resolved;
+    let command = if config.offsets {
+        let document_guard = session.document.lock().expect("Failed to lock document");
+        let encoding = session.server_for(&document_guard.language_id).encoding;
+        resolved = match resolve_offsets(command, &document_guard.source, encoding) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                errln!("{e}");
+                return Ok(None);
+            }
+        };
+        resolved.as_str()
+    } else {
+        command
+    };
+
+    let command = match parse_command(command) {
+        Ok(command) => command,
+        Err(e) => {
+            errln!("{e}");
+            let name = command.split_whitespace().next().unwrap_or_default();
+            if !config.quiet && find_command(name).is_none() {
+                errln!("Available commands: {}", command_names());
+            }
+            return Ok(None);
+        }
+    };
+
+    // Ask before taking the session locks so server output isn't held up while the user types.
+    let command = match command {
+        Command::Definition(None) if config.interactive => {
+            let Some(position) = prompt_position() else {
+                errln!("Usage: def <line> <col>");
+                return Ok(None);
+            };
+            Command::Definition(Some(position))
+        }
+        Command::References {
+            position: None,
+            include_declaration,
+        } if config.interactive => {
+            let Some(position) = prompt_position() else {
+                errln!("Usage: ref <line> <col>");
+                return Ok(None);
+            };
+            Command::References {
+                position: Some(position),
+                include_declaration,
+            }
+        }
+        command => command,
+    };
 
-    let available = "help, def, ref, sym, quit";
+    let mut count_guard = session.count.lock().expect("Failed to lock count");
+    let mut commands_guard = session.commands.lock().expect("Failed to lock commands");
+    let mut pending_guard = session
+        .pending
+        .lock()
+        .expect("Failed to lock pending requests");
+    let mut document_guard = session.document.lock().expect("Failed to lock document");
+    let file_uri = document_guard.uri.clone();
+    let file_uri = file_uri.as_str();
 
-    Ok(match command.trim() {
-        "help" => {
-            println!("Available commands: {available}");
-            commands_guard.push(json!("help"));
+    Ok(match command {
+        Command::Help(None) => {
+            status(session, &format!("Available commands: {}", command_names()));
+            status(session, "Type help <command> for its usage.");
+            None
+        }
+        Command::Help(Some(spec)) => {
+            status(session, &format!("{} — {}", spec.usage, spec.summary));
             None
         }
-        "def" => {
-            let request = definition_request(count_guard.inc(), file_uri, 9, 4);
-            let request_json = String::from_utf8_lossy(&request);
-            let json_value: Value = serde_json::from_str(
-                request_json
-                    .split("\r\n\r\n")
-                    .last()
-                    .expect("Failed to split request"),
-            )
-            .expect("Failed to parse JSON");
-            commands_guard.push(json_value);
+        Command::Definition(position) => {
+            let (line, character) = position.unwrap_or(DEFAULT_POSITION);
+            let request = definition_request(count_guard.inc(), file_uri, line, character);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Declaration(line, character) => {
+            let request = declaration_request(count_guard.inc(), file_uri, line, character);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::References {
+            position,
+            include_declaration,
+        } => {
+            let (line, character) = position.unwrap_or(DEFAULT_POSITION);
+            let request = reference_request(
+                count_guard.inc(),
+                file_uri,
+                line,
+                character,
+                include_declaration,
+            );
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Hover(line, character) => {
+            let request = hover_request(count_guard.inc(), file_uri, line, character);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Symbols(view) => {
+            let id = count_guard.inc();
+            let request = document_symbol_request(id, file_uri);
+            drop(count_guard);
+            let bytes = track_request(request, &mut commands_guard, &mut pending_guard);
+
+            if view.kind.is_some() || view.tree {
+                session
+                    .symbol_views
+                    .lock()
+                    .expect("Failed to lock symbol views")
+                    .insert(id, view);
+            }
+
+            Some(bytes)
+        }
+        Command::Goto(index) => {
+            let target = session
+                .targets
+                .lock()
+                .expect("Failed to lock targets")
+                .get(index)
+                .cloned();
+            let Some((uri, (line, character))) = target.as_ref().and_then(|target| {
+                let uri = target.get("uri")?.as_str()?;
+                let (line, character) =
+                    position_key(target.get("range").and_then(|range| range.get("start")))?;
+                Some((
+                    uri,
+                    (
+                        usize::try_from(line).ok()?,
+                        usize::try_from(character).ok()?,
+                    ),
+                ))
+            }) else {
+                errln!("No result {index}; run def, ref or sym first");
+                return Ok(None);
+            };
+
+            // A target in another file is opened first, as `open` would.
+            let mut request = Vec::new();
+            if uri != file_uri {
+                let Some(path) = uri_to_path(uri) else {
+                    errln!("Cannot open non-file URI {uri}");
+                    return Ok(None);
+                };
+                let document = match open_document(&path, config) {
+                    Ok(document) => document,
+                    Err(e) => {
+                        errln!("{e}");
+                        return Ok(None);
+                    }
+                };
+                request = did_open_request(
+                    &document.uri,
+                    &document.source,
+                    &document.language_id,
+                    document.version,
+                );
+                status(session, &format!("Opened {}", document.uri));
+                *document_guard = document;
+            }
+
+            let definition = definition_request(count_guard.inc(), uri, line, character);
+            request.extend(track_request(
+                definition,
+                &mut commands_guard,
+                &mut pending_guard,
+            ));
+            Some(request)
+        }
+        Command::Tokens => {
+            let request = semantic_tokens_request(count_guard.inc(), file_uri);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Hints(lines) => {
+            let (start, end) = lines.unwrap_or((0, document_guard.source.lines().count()));
+            let range = json!({
+                "start": { "line": start, "character": 0 },
+                "end": { "line": end, "character": 0 }
+            });
+
+            let request = inlay_hint_request(count_guard.inc(), file_uri, &range);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Fold => {
+            let request = folding_range_request(count_guard.inc(), file_uri);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Links => {
+            let request = document_link_request(count_guard.inc(), file_uri);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Select(line, character) => {
+            let request =
+                selection_range_request(count_guard.inc(), file_uri, &[(line, character)]);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Action(line, character) => {
+            let diagnostics: Vec<Value> = session
+                .diagnostics
+                .lock()
+                .expect("Failed to lock diagnostics")
+                .get(file_uri)
+                .map(|diagnostics| {
+                    diagnostics
+                        .iter()
+                        .filter(|diagnostic| diagnostic_contains(diagnostic, line, character))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+            let position = json!({ "line": line, "character": character });
+            let range = json!({ "start": position, "end": position });
+
+            let request = code_action_request(count_guard.inc(), file_uri, &range, &diagnostics);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Complete(line, character) => {
+            let request = completion_request(count_guard.inc(), file_uri, line, character);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Resolve(index) => {
+            let completions = session
+                .completions
+                .lock()
+                .expect("Failed to lock completions");
+            let Some(item) = completions.get(index) else {
+                errln!("No completion item {index}; run complete first");
+                return Ok(None);
+            };
 
+            let request = completion_resolve_request(count_guard.inc(), item);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Colors => {
+            let request = document_color_request(count_guard.inc(), file_uri);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Present(index) => {
+            let colors = session.colors.lock().expect("Failed to lock colors");
+            let Some((color, range)) = colors
+                .get(index)
+                .and_then(|information| information.get("color").zip(information.get("range")))
+            else {
+                errln!("No color {index}; run colors first");
+                return Ok(None);
+            };
+
+            let request = color_presentation_request(count_guard.inc(), file_uri, color, range);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::PrepareRename(line, character) => {
+            let request = prepare_rename_request(count_guard.inc(), file_uri, line, character);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Rename {
+            line,
+            character,
+            new_name,
+        } => {
+            // Ask the server first when it supports it, and only rename if it agrees.
+            let prepare = session
+                .server_for(&document_guard.language_id)
+                .capabilities
+                .as_ref()
+                .and_then(|capabilities| capabilities.get("renameProvider"))
+                .and_then(|provider| provider.get("prepareProvider"))
+                .and_then(Value::as_bool)
+                .unwrap_or_default();
+
+            let id = count_guard.inc();
+            let request = if prepare {
+                let request = prepare_rename_request(id, file_uri, line, character);
+                session
+                    .follow_ups
+                    .lock()
+                    .expect("Failed to lock follow-ups")
+                    .insert(
+                        id,
+                        FollowUp::Rename {
+                            uri: file_uri.to_string(),
+                            line,
+                            character,
+                            new_name,
+                        },
+                    );
+                request
+            } else {
+                rename_request(id, file_uri, line, character, &new_name)
+            };
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Format { apply } => {
+            let id = count_guard.inc();
+            let request = formatting_request(id, file_uri);
+            let bytes = track_request(request, &mut commands_guard, &mut pending_guard);
+
+            if apply {
+                session
+                    .follow_ups
+                    .lock()
+                    .expect("Failed to lock follow-ups")
+                    .insert(
+                        id,
+                        FollowUp::ApplyEdits {
+                            uri: file_uri.to_string(),
+                        },
+                    );
+            }
+
+            Some(bytes)
+        }
+        Command::Calls {
+            incoming,
+            line,
+            character,
+        } => {
+            let id = count_guard.inc();
+            let request = call_hierarchy_prepare_request(id, file_uri, line, character);
+            let bytes = track_request(request, &mut commands_guard, &mut pending_guard);
+
+            let follow_up = if incoming {
+                FollowUp::Request("callHierarchy/incomingCalls")
+            } else {
+                FollowUp::Request("callHierarchy/outgoingCalls")
+            };
+            session
+                .follow_ups
+                .lock()
+                .expect("Failed to lock follow-ups")
+                .insert(id, follow_up);
+
+            Some(bytes)
+        }
+        Command::Open(file_path) => {
+            let document = match open_document(&file_path, config) {
+                Ok(document) => document,
+                Err(e) => {
+                    errln!("{e}");
+                    return Ok(None);
+                }
+            };
+
+            // Opening the active file again is a reopen: the server already has it open.
+            let mut request = Vec::new();
+            if document.uri == *file_uri {
+                request = did_close_request(file_uri);
+                document_guard.source = document.source;
+                document_guard.version += 1;
+                status(session, &format!("Reopened {}", file_path.display()));
+            } else {
+                status(session, &format!("Opened {}", document.uri));
+                *document_guard = document;
+            }
+
+            request.extend(did_open_request(
+                &document_guard.uri,
+                &document_guard.source,
+                &document_guard.language_id,
+                document_guard.version,
+            ));
             Some(request)
         }
-        "ref" => {
-            let request = reference_request(count_guard.inc(), file_uri, 9, 4);
-            let request_json = String::from_utf8_lossy(&request);
-            let json_value: Value = serde_json::from_str(
-                request_json
-                    .split("\r\n\r\n")
-                    .last()
-                    .expect("Failed to split request"),
-            )
-            .expect("Failed to parse JSON");
-            commands_guard.push(json_value);
+        Command::Edit { start, end, text } => {
+            let range = json!({
+                "start": { "line": start.0, "character": start.1 },
+                "end": { "line": end.0, "character": end.1 }
+            });
+            let encoding = session.server_for(&document_guard.language_id).encoding;
+            let edit = json!({ "range": range, "newText": text });
+            let source = match apply_edits(&document_guard.source, &[edit], encoding) {
+                Ok(source) => source,
+                Err(e) => {
+                    errln!("{e}");
+                    return Ok(None);
+                }
+            };
+            document_guard.source = source;
+            document_guard.version += 1;
+
+            match session.sync_kind {
+                SyncKind::None => None,
+                SyncKind::Full => Some(did_change_request(
+                    file_uri,
+                    document_guard.version,
+                    &document_guard.source,
+                )),
+                SyncKind::Incremental => Some(did_change_range_request(
+                    file_uri,
+                    document_guard.version,
+                    &range,
+                    &text,
+                )),
+            }
+        }
+        Command::Raw(message) => {
+            let request = raw_request(count_guard.inc(), message);
+            Some(track_request(
+                request,
+                &mut commands_guard,
+                &mut pending_guard,
+            ))
+        }
+        Command::Reopen => {
+            let Some(path) = uri_to_path(file_uri) else {
+                errln!("Cannot reopen non-file URI {file_uri}");
+                return Ok(None);
+            };
+            let source = match process_file(&path) {
+                Ok((_, source)) => source,
+                Err(e) => {
+                    errln!("{e}");
+                    return Ok(None);
+                }
+            };
+
+            // A clean close and open, for servers that only fully reset state that way.
+            document_guard.source = source;
+            document_guard.version += 1;
+            let mut request = did_close_request(file_uri);
+            request.extend(did_open_request(
+                file_uri,
+                &document_guard.source,
+                &document_guard.language_id,
+                document_guard.version,
+            ));
+            status(session, &format!("Reopened {}", path.display()));
 
             Some(request)
         }
-        "sym" => {
-            let request = document_symbol_request(count_guard.inc(), file_uri);
-            drop(count_guard);
-            let request_json = String::from_utf8_lossy(&request);
-            let json_value: Value = serde_json::from_str(
-                request_json
-                    .split("\r\n\r\n")
-                    .last()
-                    .expect("Failed to split request"),
-            )
-            .expect("Failed to parse JSON");
-            commands_guard.push(json_value);
+        Command::Reload => {
+            let Some(path) = uri_to_path(file_uri) else {
+                errln!("Cannot reload non-file URI {file_uri}");
+                return Ok(None);
+            };
+            let source = match fs::read_to_string(&path) {
+                Ok(source) => source,
+                Err(e) => {
+                    errln!("Failed to read {}: {e}", path.display());
+                    return Ok(None);
+                }
+            };
+
+            document_guard.source = source;
+            document_guard.version += 1;
+            status(session, &format!("Reloaded {}", path.display()));
+
+            Some(document_changed(&document_guard, session.sync_kind))
+        }
+        Command::Save { text } => {
+            let include_text = text
+                || server_wants_saved_text(
+                    session
+                        .server_for(&document_guard.language_id)
+                        .capabilities
+                        .as_ref(),
+                );
+
+            let mut request = will_save_request(file_uri, SAVE_MANUAL);
+            request.extend(did_save_request(
+                file_uri,
+                include_text,
+                &document_guard.source,
+            ));
             Some(request)
         }
-        "quit" => {
-            commands_guard.push(json!("quit"));
+        Command::Server(command) => {
+            // The server pool belongs to the caller, which makes the switch.
+            *session
+                .server_switch
+                .lock()
+                .expect("Failed to lock server switch") = Some(command);
             None
         }
-        _ => {
-            eprintln!("Unknown command: {}", command.trim());
-            eprintln!("Available commands: {available}");
-            commands_guard.push(json!("unknown"));
+        Command::Config(settings) => {
+            let settings = Value::Object(settings);
+            let request = did_change_configuration_request(&settings);
+            *session.settings.lock().expect("Failed to lock settings") = settings;
+            Some(request)
+        }
+        Command::Caps => {
+            let capabilities = session.server_for(&document_guard.language_id).capabilities;
+            write_output(
+                session,
+                &display_capabilities(capabilities.as_ref(), session.format),
+            );
             None
         }
+        Command::Cancel(id) => {
+            if pending_guard.remove(&id).is_none() {
+                errln!("Request {id} is not pending");
+                return Ok(None);
+            }
+
+            status(session, &format!("Cancelled request {id}"));
+            Some(cancel_notification(id))
+        }
+        // The caller ends the session on quit before it gets here.
+        Command::Quit => None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "int x;\nint y;\n";
+
+    #[test]
+    fn initialize_request_agrees_on_the_root() {
+        let root = Path::new("/home/me/my project");
+        let params = &initialize_request(1, root, None).message["params"];
+        assert_eq!(params["rootPath"], "/home/me/my project");
+        assert_eq!(params["rootUri"], "file:///home/me/my%20project");
+        assert_eq!(
+            params["workspaceFolders"],
+            json!([{ "uri": "file:///home/me/my%20project", "name": "my project" }])
+        );
+        assert_eq!(
+            uri_to_path(params["rootUri"].as_str().unwrap()).as_deref(),
+            Some(root)
+        );
+    }
+
+    #[test]
+    fn generate_rpc_request_frames_the_body() {
+        let frame = generate_rpc_request(&create_request("shutdown", &Value::Null, Some(2)));
+        assert_eq!(
+            frame,
+            b"Content-Length: 58\r\n\r\n{\"id\":2,\"jsonrpc\":\"2.0\",\"method\":\"shutdown\",\"params\":null}"
+        );
+    }
+
+    #[test]
+    fn generate_rpc_request_counts_bytes_not_chars() {
+        let frame = generate_rpc_request(&json!({ "text": "é" }));
+        assert_eq!(
+            frame,
+            "Content-Length: 13\r\n\r\n{\"text\":\"é\"}".as_bytes()
+        );
+    }
+
+    #[test]
+    fn resolve_offsets_rewrites_position_slots() {
+        let resolved = resolve_offsets("def @8", SOURCE, PositionEncoding::Utf8).unwrap();
+        assert_eq!(resolved, "def 1 1");
+
+        let resolved = resolve_offsets("edit @0 @4 @", SOURCE, PositionEncoding::Utf8).unwrap();
+        assert_eq!(resolved, "edit 0 0 0 4 @");
+    }
+
+    #[test]
+    fn resolve_offsets_leaves_edit_text_alone() {
+        let resolved = resolve_offsets("edit 0 0 0 0 @3", SOURCE, PositionEncoding::Utf8).unwrap();
+        assert_eq!(resolved, "edit 0 0 0 0 @3");
+
+        let resolved = resolve_offsets("rename 0 4 @x", SOURCE, PositionEncoding::Utf8).unwrap();
+        assert_eq!(resolved, "rename 0 4 @x");
+    }
+}
@@ -0,0 +1,143 @@
+use crate::display::Colors;
+use crate::error::LspError;
+use crate::outln;
+use serde_json::Value;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+pub fn start_server_process(
+    command: &str,
+    args: &[String],
+    working_dir: Option<&Path>,
+    env: &[(String, String)],
+) -> Result<std::process::Child, LspError> {
+    let mut process = Command::new(command);
+    if let Some(working_dir) = working_dir {
+        process.current_dir(working_dir);
+    }
+    process
+        .args(args)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound if command.contains('/') => LspError::Usage(format!(
+                "Language server '{command}' not found. Check the path to it."
+            )),
+            io::ErrorKind::NotFound => LspError::Usage(format!(
+                "Language server '{command}' not found on PATH. Install it or pass --command."
+            )),
+            _ => LspError::io("Failed to start server", e),
+        })
+}
+
+pub type ServerStreams = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
+pub fn connect_socket(address: &str) -> Result<ServerStreams, LspError> {
+    let stream = TcpStream::connect(address)
+        .map_err(|e| LspError::io(format!("Failed to connect to {address}"), e))?;
+    let writer = stream
+        .try_clone()
+        .map_err(|e| LspError::io("Failed to clone socket", e))?;
+
+    Ok((Box::new(stream), Box::new(writer)))
+}
+
+// The exact bytes of a frame on one line, with \r, \n and anything unprintable escaped.
+pub fn escape_frame(frame: &[u8]) -> String {
+    let mut escaped = String::with_capacity(frame.len());
+    for &byte in frame {
+        match byte {
+            b'\r' => escaped.push_str("\\r"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(char::from(byte)),
+            _ => escaped.push_str(&format!("\\x{byte:02x}")),
+        }
+    }
+    escaped
+}
+
+pub fn consume_json_rpc_message(
+    reader: &mut BufReader<impl Read>,
+    colors: Colors,
+    echo_raw: bool,
+) -> Result<Option<Value>, LspError> {
+    let mut length = None;
+    let mut first = true;
+    let mut frame = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader
+            .read_line(&mut line)
+            .map_err(|e| LspError::io("Failed to read line from stdout", e))?
+            == 0
+        {
+            if first {
+                return Ok(None); // EOF
+            }
+            return Err(LspError::protocol(
+                "Unexpected end of stream in message headers",
+            ));
+        }
+        first = false;
+        frame.extend_from_slice(line.as_bytes());
+
+        // Headers end at the first blank line.
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(LspError::protocol(format!(
+                "Unexpected line: {}{line}{}",
+                colors.red, colors.normal
+            )));
+        };
+
+        if name.trim().eq_ignore_ascii_case("Content-Length") {
+            length =
+                Some(value.trim().parse::<usize>().map_err(|e| {
+                    LspError::protocol(format!("Failed to parse Content-Length: {e}"))
+                })?);
+        }
+    }
+
+    let length =
+        length.ok_or_else(|| LspError::protocol("Message is missing a Content-Length header"))?;
+
+    // Read the JSON message
+    let mut json_buffer = vec![0; length];
+    reader
+        .read_exact(&mut json_buffer)
+        .map_err(|e| LspError::io("Failed to read JSON message", e))?;
+
+    if echo_raw {
+        frame.extend_from_slice(&json_buffer);
+        outln!("<-- {}", escape_frame(&frame));
+    }
+
+    let json_str = String::from_utf8_lossy(&json_buffer);
+    let json_str = json_str.trim_end();
+    if json_str.is_empty() {
+        return Err(LspError::protocol("Received empty JSON message"));
+    }
+
+    serde_json::from_str::<Value>(json_str)
+        .map(Some)
+        .map_err(|e| {
+            LspError::json(
+                format!(
+                    "Failed to parse JSON message: {}{json_str}{}",
+                    colors.yellow, colors.normal
+                ),
+                e,
+            )
+        })
+}